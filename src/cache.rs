@@ -0,0 +1,204 @@
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use memmap2::Mmap;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::seek::SeekIndex;
+use crate::timeline::Timeline;
+
+// Bump when the layout below or the way events are merged changes.
+const MAGIC: &[u8; 8] = b"UMIDIEV5";
+const CHECKSUM_OFFSET: u64 = 40;
+const HEADER_SIZE: usize = 48;
+
+/// Most bytes of cached events kept. Past it the least recently used are deleted.
+const CACHE_LIMIT: u64 = 8 << 30;
+
+/// Identifies a merged event stream: the MIDI file plus the settings that change event times.
+#[derive(Clone, Copy, PartialEq)]
+pub struct CacheKey {
+    file_id: u64,
+    playback_speed: f64,
+}
+
+impl CacheKey {
+    /// Identifies a MIDI file by its path, size and modification time, so the cache can
+    /// be checked before anything is read. None for stdin, which can't be told apart.
+    pub fn for_file(path: &str, playback_speed: f64) -> Option<CacheKey> {
+        if path == "-" {
+            return None;
+        }
+        let path = fs::canonicalize(path).ok()?;
+        let metadata = fs::metadata(&path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        let mut id = Xxh3::new();
+        id.update(path.to_string_lossy().as_bytes());
+        id.update(&metadata.len().to_le_bytes());
+        id.update(&modified.as_nanos().to_le_bytes());
+        Some(CacheKey {
+            file_id: id.digest(),
+            playback_speed,
+        })
+    }
+
+    /// Where the events for this key are cached, in the user's cache directory.
+    pub fn path(&self) -> PathBuf {
+        let dir = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+        dir.join("UniMIDI").join(format!("{:016x}-{:016x}.events", self.file_id, self.playback_speed.to_bits()))
+    }
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Maps the cached events for `key` and reads their seek index, if there are any and they're intact.
+///
+/// Layout: magic, file hash, playback speed, event count, SysEx count and a checksum
+/// in the 48 byte header, then the timeline columns, then each SysEx message as its
+/// event index, length and bytes, then the seek snapshots. Everything is little endian.
+///
+/// The checksum covers the header before it and everything after the columns. The
+/// columns themselves aren't read until they play, so loading stays instant however big
+/// the cache is. A damaged column only plays wrong notes, see [`Timeline::event`].
+pub fn load(key: CacheKey) -> Option<(Timeline, SeekIndex)> {
+    let path = key.path();
+    let file = File::open(&path).ok()?;
+    // Safe as long as nobody truncates the file while it's mapped, the cache is only
+    // ever replaced by renaming a new file over it.
+    let map = unsafe { Mmap::map(&file) }.ok()?;
+
+    if map.get(..8)? != MAGIC
+        || read_u64(&map, 8)? != key.file_id
+        || read_u64(&map, 16)? != key.playback_speed.to_bits()
+    {
+        return None;
+    }
+
+    let len = read_u64(&map, 24)? as usize;
+    let meta_count = read_u64(&map, 32)? as usize;
+    let mut at = HEADER_SIZE.checked_add(len.checked_mul(7)?)?;
+    if at > map.len() {
+        return None;
+    }
+
+    let mut checksum = Xxh3::new();
+    checksum.update(&map[..CHECKSUM_OFFSET as usize]);
+    checksum.update(&map[at..]);
+    if read_u64(&map, CHECKSUM_OFFSET as usize)? != checksum.digest() {
+        return None;
+    }
+
+    let mut meta = Vec::with_capacity(meta_count.min(map.len() / 12));
+    for _ in 0..meta_count {
+        let index = read_u64(&map, at)? as usize;
+        let size = u32::from_le_bytes(map.get(at + 8..at + 12)?.try_into().ok()?) as usize;
+        let data = map.get(at + 12..at + 12 + size)?;
+        if index >= len || data.first() != Some(&0xF0) {
+            return None;
+        }
+        meta.push((index, data.into()));
+        at += 12 + size;
+    }
+
+    let index = SeekIndex::from_bytes(map.get(at..)?, len, meta.len())?;
+    let timeline = Timeline::mapped(map, HEADER_SIZE, len, meta);
+    if !timeline.meta_matches() {
+        return None;
+    }
+
+    // Marks it as used for `prune`. Not being able to is no reason not to play it.
+    if let Ok(file) = OpenOptions::new().write(true).open(&path) {
+        file.set_modified(SystemTime::now()).ok();
+    }
+    Some((timeline, index))
+}
+
+/// Writes `timeline` and its seek index to the cache for `key`, replacing what was there.
+pub fn save(key: CacheKey, timeline: &Timeline, index: &SeekIndex) -> io::Result<()> {
+    let path = key.path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Written next to the real file first so a half written cache is never loaded.
+    let partial = path.with_extension("partial");
+    {
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&key.file_id.to_le_bytes());
+        header.extend_from_slice(&key.playback_speed.to_bits().to_le_bytes());
+        header.extend_from_slice(&(timeline.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(timeline.meta().len() as u64).to_le_bytes());
+        let mut checksum = Xxh3::new();
+        checksum.update(&header);
+        // The checksum, filled in once everything it covers is written.
+        header.resize(HEADER_SIZE, 0);
+        writer.write_all(&header)?;
+
+        // Not covered by the checksum, see `load`.
+        for column in timeline.columns().iter() {
+            writer.write_all(column)?;
+        }
+
+        let mut write = |bytes: &[u8]| {
+            checksum.update(bytes);
+            writer.write_all(bytes)
+        };
+
+        for (index, data) in timeline.meta() {
+            write(&(*index as u64).to_le_bytes())?;
+            write(&(data.len() as u32).to_le_bytes())?;
+            write(data)?;
+        }
+        write(&index.to_bytes())?;
+
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(CHECKSUM_OFFSET))?;
+        file.write_all(&checksum.digest().to_le_bytes())?;
+    }
+
+    fs::rename(&partial, &path)?;
+    prune(&path);
+    Ok(())
+}
+
+/// Deletes the least recently used caches until they fit in [`CACHE_LIMIT`] along with
+/// `keep`, which is never deleted. Caches that can't be deleted, e.g. because another
+/// instance is playing them on Windows, are left alone.
+fn prune(keep: &Path) {
+    let entries = match keep.parent().map(fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return,
+    };
+
+    let mut total = fs::metadata(keep).map(|m| m.len()).unwrap_or(0);
+    let mut caches = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path == keep || path.extension() != Some(OsStr::new("events")) {
+            continue;
+        }
+        if let Ok(metadata) = entry.metadata() {
+            total += metadata.len();
+            caches.push((metadata.modified().unwrap_or(UNIX_EPOCH), metadata.len(), path));
+        }
+    }
+
+    caches.sort();
+    for (_, size, path) in caches {
+        if total <= CACHE_LIMIT {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= size;
+        }
+    }
+}
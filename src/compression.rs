@@ -0,0 +1,40 @@
+use std::io::{self, Read};
+
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+/// How long a file's start has to be to tell whether it's compressed.
+pub const MAGIC_LEN: usize = 6;
+
+/// The formats black MIDIs are usually shared in, recognized by their first bytes
+/// rather than the file extension.
+#[derive(Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(start: &[u8]) -> Option<Compression> {
+        if start.starts_with(&[0x1F, 0x8B]) {
+            Some(Compression::Gzip)
+        } else if start.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if start.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Wraps `reader` so the decompressed data is read from it. Files made of several
+    /// concatenated streams are read to the end.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+}
@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static HEADLESS: AtomicBool = AtomicBool::new(false);
+
+/// Moves all of UniMIDI's own text to stderr and turns off the visualizer, for when
+/// MIDI data or tapped events are written to stdout and anything else would end up in it.
+pub fn set_headless(headless: bool) {
+    HEADLESS.store(headless, Ordering::Relaxed);
+}
+
+pub fn headless() -> bool {
+    HEADLESS.load(Ordering::Relaxed)
+}
+
+/// `println!` that goes to stderr instead when running headless.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::console::headless() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,694 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+use memmap2::Mmap;
+use rayon::prelude::*;
+
+use crate::compression::{Compression, MAGIC_LEN};
+use crate::progress::{LoadProgress, Phase, UNKNOWN_TOTAL};
+use crate::smf::{Division, Smf, TrackEvent, TrackReader};
+use crate::timeline::Timeline;
+
+/// How often, in events, loading reports progress and checks for cancellation. A power of two.
+pub const REPORT_INTERVAL: u64 = 1 << 16;
+
+/// Merges shorter than this are done by one thread.
+const MIN_PARALLEL_MERGE: usize = 1 << 16;
+
+pub enum LoadError {
+    Cancelled,
+    Invalid(String),
+}
+
+/// Turns ticks into seconds, following tempo changes.
+struct Clock {
+    seconds_per_tick: f64,
+    tick: u64,
+    time: f64,
+    division: Division,
+    speed: f64,
+}
+
+impl Clock {
+    fn new(division: Division, playback_speed: f64) -> Clock {
+        let mut clock = Clock {
+            seconds_per_tick: 0.0,
+            tick: 0,
+            time: 0.0,
+            division,
+            speed: playback_speed,
+        };
+        match division {
+            Division::Ppq(_) => clock.set_tempo(500000),
+            Division::Smpte { frames_per_second, ticks_per_frame } => {
+                clock.seconds_per_tick = 1.0 / (frames_per_second * ticks_per_frame as f64) / playback_speed;
+            }
+        }
+        clock
+    }
+
+    fn set_tempo(&mut self, tempo: u32) {
+        // SMPTE ticks are a fixed length.
+        if let Division::Ppq(ppq) = self.division {
+            self.seconds_per_tick = tempo as f64 / 1_000_000.0 / ppq as f64 / self.speed;
+        }
+    }
+
+    /// Moves on to `tick`, which can't be before the last one.
+    fn advance(&mut self, tick: u64) {
+        self.time += (tick - self.tick) as f64 * self.seconds_per_tick;
+        self.tick = tick;
+    }
+}
+
+/// An event from the merged tracks, with its time in seconds since the previous one.
+pub struct MergedEvent<'a> {
+    pub delta: f64,
+    pub event: TrackEvent<'a>,
+}
+
+/// Merges every track of a file in time order as it's iterated, converting ticks to seconds.
+/// Used when events are needed before the whole file could be loaded, [`load`] is faster otherwise.
+///
+/// Events at the same tick keep the track order. Tempo events are consumed, and
+/// a `Port` event is inserted whenever the next channel message comes from a track
+/// on a different port, the same as [`load`] does.
+pub struct MergedEvents<'a> {
+    readers: Vec<TrackReader<'a>>,
+    pending: Vec<Option<TrackEvent<'a>>>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    track_ports: Vec<u8>,
+    port: u8,
+    queued: Option<TrackEvent<'a>>,
+    clock: Clock,
+    last_time: f64,
+}
+
+impl<'a> MergedEvents<'a> {
+    pub fn new(smf: &Smf<'a>, playback_speed: f64) -> MergedEvents<'a> {
+        let mut merged = MergedEvents {
+            readers: smf.tracks.iter().map(|t| TrackReader::new(t)).collect(),
+            pending: vec![None; smf.tracks.len()],
+            heap: BinaryHeap::with_capacity(smf.tracks.len()),
+            track_ports: vec![0; smf.tracks.len()],
+            port: 0,
+            queued: None,
+            clock: Clock::new(smf.division, playback_speed),
+            last_time: 0.0,
+        };
+        for track in 0..merged.readers.len() {
+            merged.advance(track);
+        }
+        merged
+    }
+
+    fn advance(&mut self, track: usize) {
+        if let Some((tick, event)) = self.readers[track].next() {
+            self.pending[track] = Some(event);
+            self.heap.push(Reverse((tick, track)));
+        }
+    }
+
+    fn emit(&mut self, event: TrackEvent<'a>) -> MergedEvent<'a> {
+        let delta = self.clock.time - self.last_time;
+        self.last_time = self.clock.time;
+        MergedEvent { delta, event }
+    }
+}
+
+impl<'a> Iterator for MergedEvents<'a> {
+    type Item = MergedEvent<'a>;
+
+    fn next(&mut self) -> Option<MergedEvent<'a>> {
+        if let Some(event) = self.queued.take() {
+            return Some(self.emit(event));
+        }
+
+        loop {
+            let Reverse((tick, track)) = self.heap.pop()?;
+            let event = self.pending[track].take().unwrap();
+            self.advance(track);
+            self.clock.advance(tick);
+
+            match event {
+                TrackEvent::Tempo(tempo) => self.clock.set_tempo(tempo),
+                TrackEvent::Port(port) => self.track_ports[track] = port,
+                // SysEx messages aren't sent per port, so they don't switch it either.
+                TrackEvent::SysEx(_) => return Some(self.emit(event)),
+                _ => {
+                    let port = self.track_ports[track];
+                    if port != self.port {
+                        self.port = port;
+                        self.queued = Some(event);
+                        return Some(self.emit(TrackEvent::Port(port)));
+                    }
+                    return Some(self.emit(event));
+                }
+            }
+        }
+    }
+}
+
+/// Where a MIDI file is read from: a file, or stdin when the path is `-`.
+enum Input {
+    File(File),
+    // The bytes checked for compression are put back in front.
+    Stdin(io::Chain<io::Cursor<Vec<u8>>, io::Stdin>),
+}
+
+impl Input {
+    /// `None` for stdin, which is read until it ends.
+    fn size(&self) -> io::Result<Option<u64>> {
+        match self {
+            Input::File(file) => Ok(Some(file.metadata()?.len())),
+            Input::Stdin(_) => Ok(None),
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            Input::Stdin(stdin) => stdin.read(buf),
+        }
+    }
+}
+
+/// Opens a file, or stdin for `-`, and checks whether it's compressed.
+fn open(path: &str) -> io::Result<(Input, Option<Compression>)> {
+    let mut start = Vec::with_capacity(MAGIC_LEN);
+    if path == "-" {
+        io::stdin().take(MAGIC_LEN as u64).read_to_end(&mut start)?;
+        let compression = Compression::detect(&start);
+        return Ok((Input::Stdin(io::Cursor::new(start).chain(io::stdin())), compression));
+    }
+
+    let mut file = File::open(path)?;
+    (&mut file).take(MAGIC_LEN as u64).read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok((Input::File(file), Compression::detect(&start)))
+}
+
+/// Passes reads through, reporting how much of the input they got through.
+struct ProgressReader<'a> {
+    input: Input,
+    read: u64,
+    progress: &'a LoadProgress,
+}
+
+impl Read for ProgressReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.input.read(buf)?;
+        self.read += read as u64;
+        self.progress.set(self.read);
+        Ok(read)
+    }
+}
+
+/// Copies the input from [`open`] into `out`, decompressing it if it's compressed.
+fn copy(path: &str, input: Input, compression: Option<Compression>, out: &mut impl Write, progress: &LoadProgress) -> Result<(), LoadError> {
+    let invalid = |e: io::Error| LoadError::Invalid(format!("{}: {}", path, e));
+    let size = input.size().map_err(invalid)?;
+    let phase = if compression.is_some() { Phase::Decompressing } else { Phase::Reading };
+    progress.start(phase, size.unwrap_or(UNKNOWN_TOTAL));
+
+    // The progress follows the input either way, the decompressed size isn't known up front.
+    let input = ProgressReader { input, read: 0, progress };
+    let mut reader: Box<dyn Read> = match compression {
+        Some(compression) => compression.decoder(input).map_err(invalid)?,
+        None => Box::new(input),
+    };
+
+    let mut buf = vec![0; 1 << 22];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(invalid(e)),
+        };
+        out.write_all(&buf[..read]).map_err(invalid)?;
+        if progress.is_cancelled() {
+            return Err(LoadError::Cancelled);
+        }
+    }
+    if size.is_none() {
+        progress.finish_phase();
+    }
+    Ok(())
+}
+
+/// Reads a whole file, or stdin for `-`, into memory, decompressing it if it's compressed.
+pub fn read_file(path: &str, progress: &LoadProgress) -> Result<Vec<u8>, LoadError> {
+    let invalid = |e: io::Error| LoadError::Invalid(format!("{}: {}", path, e));
+    let (input, compression) = open(path).map_err(invalid)?;
+
+    let mut bytes = Vec::new();
+    if let (Some(size), None) = (input.size().map_err(invalid)?, compression) {
+        bytes.reserve_exact(size as usize);
+    }
+    copy(path, input, compression, &mut bytes, progress)?;
+    Ok(bytes)
+}
+
+/// Maps a file without reading it, for streaming.
+///
+/// Compressed files and stdin can't be mapped as they are, so they're copied into a
+/// temporary file first, which is deleted again once the player exits.
+pub fn map_file(path: &str, progress: &LoadProgress) -> Result<Mmap, LoadError> {
+    let invalid = |e: io::Error| LoadError::Invalid(format!("{}: {}", path, e));
+    let file = match open(path).map_err(invalid)? {
+        (Input::File(file), None) => file,
+        (input, compression) => {
+            let temp_path = std::env::temp_dir().join(format!("UniMIDI-{}.mid", std::process::id()));
+            let temp_file = create_temp(&temp_path).map_err(invalid)?;
+
+            let mut writer = BufWriter::new(&temp_file);
+            let copied = copy(path, input, compression, &mut writer, progress);
+            let flushed = writer.flush().map_err(invalid);
+            drop(writer);
+            // Nothing else needs the name. Windows deletes the file once it's closed instead.
+            #[cfg(not(windows))]
+            std::fs::remove_file(&temp_path).ok();
+            copied.and(flushed)?;
+
+            temp_file
+        }
+    };
+
+    // Only unsound if the file is truncated while it's playing.
+    unsafe { Mmap::map(&file) }.map_err(invalid)
+}
+
+fn create_temp(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+        options.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+    }
+    options.open(path)
+}
+
+// While loading, every event is a tick plus a word. Channel messages keep their usual
+// packing with the track's port in the top byte, tempo changes and SysEx messages get
+// status bytes channel messages can't have, with the tempo or a SysEx index above them.
+const WORD_SYSEX: u32 = 0xF0;
+const WORD_TEMPO: u32 = 0xFF;
+
+/// A tick as stored while loading. Almost every file ends before tick 2^32, and then
+/// 32 bit ticks take a third less memory per event than 64 bit ones.
+trait Tick: Copy + Ord + Default + Send + Sync {
+    fn from_u64(tick: u64) -> Self;
+    fn to_u64(self) -> u64;
+}
+
+impl Tick for u32 {
+    fn from_u64(tick: u64) -> u32 {
+        tick as u32
+    }
+
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+impl Tick for u64 {
+    fn from_u64(tick: u64) -> u64 {
+        tick
+    }
+
+    fn to_u64(self) -> u64 {
+        self
+    }
+}
+
+/// Events as two columns. While merging these hold sorted runs back to back.
+#[derive(Default)]
+struct Events<T> {
+    ticks: Vec<T>,
+    words: Vec<u32>,
+}
+
+impl<T: Tick> Events<T> {
+    fn zeroed(len: usize) -> Events<T> {
+        Events {
+            ticks: vec![T::default(); len],
+            words: vec![0; len],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+}
+
+/// What a first pass over a track finds, so it can be decoded straight into place.
+#[derive(Clone, Copy, Default)]
+struct TrackCounts {
+    events: usize,
+    sysex: usize,
+    last_tick: u64,
+}
+
+/// Goes through a track's events, reporting progress and checking for cancellation on the way.
+fn read_track<'a>(data: &'a [u8], progress: &LoadProgress, mut f: impl FnMut(u64, TrackEvent<'a>)) -> Result<(), LoadError> {
+    let mut reader = TrackReader::new(data);
+    let mut count = 0u64;
+    let mut reported = 0;
+    while let Some((tick, event)) = reader.next() {
+        f(tick, event);
+
+        count += 1;
+        if count & (REPORT_INTERVAL - 1) == 0 {
+            if progress.is_cancelled() {
+                return Err(LoadError::Cancelled);
+            }
+            progress.add((reader.position() - reported) as u64);
+            reported = reader.position();
+        }
+    }
+    progress.add((data.len() - reported) as u64);
+    Ok(())
+}
+
+/// Counts the events of a track that are kept while loading. Port changes aren't, they
+/// go into the words of the events after them.
+fn count_track(data: &[u8], progress: &LoadProgress) -> Result<TrackCounts, LoadError> {
+    let mut counts = TrackCounts::default();
+    read_track(data, progress, |tick, event| {
+        match event {
+            TrackEvent::Port(_) => return,
+            TrackEvent::SysEx(_) => counts.sysex += 1,
+            _ => {}
+        }
+        counts.events += 1;
+        counts.last_tick = tick;
+    })?;
+    Ok(counts)
+}
+
+/// Decodes a track into `ticks` and `words`, which are exactly as long as it has events.
+/// Its SysEx messages are numbered from `first_sysex` on and returned separately.
+fn decode_track<'a, T: Tick>(data: &'a [u8], first_sysex: usize, ticks: &mut [T], words: &mut [u32], progress: &LoadProgress) -> Result<Vec<&'a [u8]>, LoadError> {
+    let mut sysex = Vec::new();
+    let mut port = 0u32;
+    let mut i = 0;
+
+    read_track(data, progress, |tick, event| {
+        let word = match event {
+            TrackEvent::Short(message) => message | port << 24,
+            TrackEvent::SysEx(data) => {
+                sysex.push(data);
+                WORD_SYSEX | ((first_sysex + sysex.len() - 1) as u32) << 8
+            }
+            TrackEvent::Tempo(tempo) => WORD_TEMPO | tempo << 8,
+            TrackEvent::Port(p) => {
+                port = p as u32;
+                return;
+            }
+        };
+        ticks[i] = T::from_u64(tick);
+        words[i] = word;
+        i += 1;
+    })?;
+    progress.track_done();
+
+    Ok(sysex)
+}
+
+/// Merges the sorted runs `a` and `b` into `ticks` and `words`, which are exactly as long
+/// as both of them. Ties go to `a`, so the track order is kept.
+fn merge_into<T: Tick>(a: (&[T], &[u32]), b: (&[T], &[u32]), ticks: &mut [T], words: &mut [u32], progress: &LoadProgress) {
+    if ticks.len() < MIN_PARALLEL_MERGE {
+        let (mut i, mut j) = (0, 0);
+        for (tick, word) in ticks.iter_mut().zip(words.iter_mut()) {
+            if j == b.0.len() || (i < a.0.len() && a.0[i] <= b.0[j]) {
+                *tick = a.0[i];
+                *word = a.1[i];
+                i += 1;
+            } else {
+                *tick = b.0[j];
+                *word = b.1[j];
+                j += 1;
+            }
+        }
+        progress.add(ticks.len() as u64);
+        return;
+    }
+
+    // Halve the longer run and split the other one where it stops coming first,
+    // then both halves can be merged on their own.
+    let (i, j) = if a.0.len() >= b.0.len() {
+        let i = a.0.len() / 2;
+        (i, b.0.partition_point(|&t| t < a.0[i]))
+    } else {
+        let j = b.0.len() / 2;
+        (a.0.partition_point(|&t| t <= b.0[j]), j)
+    };
+
+    let (ticks_low, ticks_high) = ticks.split_at_mut(i + j);
+    let (words_low, words_high) = words.split_at_mut(i + j);
+    rayon::join(
+        || merge_into((&a.0[..i], &a.1[..i]), (&b.0[..j], &b.1[..j]), ticks_low, words_low, progress),
+        || merge_into((&a.0[i..], &a.1[i..]), (&b.0[j..], &b.1[j..]), ticks_high, words_high, progress),
+    );
+}
+
+/// Merges the sorted runs in `events`, which start at `bounds` (followed by the total length),
+/// two at a time until one is left. Each level merges into a second buffer of the same size,
+/// and the two swap places for the next, so nothing else is allocated.
+fn merge_runs<T: Tick>(mut events: Events<T>, mut bounds: Vec<usize>, progress: &LoadProgress) -> Result<Events<T>, LoadError> {
+    let mut other = Events::zeroed(events.len());
+
+    while bounds.len() > 2 {
+        if progress.is_cancelled() {
+            return Err(LoadError::Cancelled);
+        }
+
+        // Split both buffers into the pairs of runs and what they're merged into.
+        let mut pairs = Vec::with_capacity(bounds.len() / 2);
+        let (mut ticks, mut words) = (&events.ticks[..], &events.words[..]);
+        let (mut out_ticks, mut out_words) = (&mut other.ticks[..], &mut other.words[..]);
+        for pair in bounds.windows(3).step_by(2) {
+            let (a_len, b_len) = (pair[1] - pair[0], pair[2] - pair[1]);
+            let (a_ticks, rest) = ticks.split_at(a_len);
+            let (b_ticks, rest) = rest.split_at(b_len);
+            ticks = rest;
+            let (a_words, rest) = words.split_at(a_len);
+            let (b_words, rest) = rest.split_at(b_len);
+            words = rest;
+            let (pair_ticks, rest) = mem::take(&mut out_ticks).split_at_mut(a_len + b_len);
+            out_ticks = rest;
+            let (pair_words, rest) = mem::take(&mut out_words).split_at_mut(a_len + b_len);
+            out_words = rest;
+            pairs.push(((a_ticks, a_words), (b_ticks, b_words), pair_ticks, pair_words));
+        }
+        // A run left without a partner moves on as it is.
+        out_ticks.copy_from_slice(ticks);
+        out_words.copy_from_slice(words);
+        progress.add(ticks.len() as u64);
+
+        pairs.into_par_iter().for_each(|(a, b, ticks, words)| merge_into(a, b, ticks, words, progress));
+
+        mem::swap(&mut events, &mut other);
+        bounds = bounds.iter().step_by(2).copied().chain(bounds.last().copied()).collect();
+        bounds.dedup();
+    }
+    Ok(events)
+}
+
+/// How many events [`merge_runs`] writes for `runs` runs with `len` events in total.
+fn merge_work(runs: usize, len: usize) -> u64 {
+    let mut levels = 0;
+    while 1 << levels < runs {
+        levels += 1;
+    }
+    levels as u64 * len as u64
+}
+
+/// Parses a whole MIDI file into a timeline, reporting progress as it goes.
+///
+/// Tracks are counted and then decoded on every core, merged level by level with
+/// parallel merges, and finally timed in one pass since tempo changes affect
+/// everything after them.
+pub fn load(bytes: &[u8], playback_speed: f64, progress: &LoadProgress) -> Result<Timeline, LoadError> {
+    let smf = Smf::parse(bytes).map_err(LoadError::Invalid)?;
+
+    let track_bytes: usize = smf.tracks.iter().map(|t| t.len()).sum();
+    progress.set_tracks(0, smf.tracks.len() as u64);
+
+    // Counted first so every track can be decoded straight into one exactly sized buffer.
+    progress.start(Phase::Counting, track_bytes as u64);
+    let counts = smf.tracks.par_iter().map(|track| count_track(track, progress)).collect::<Result<Vec<_>, _>>()?;
+
+    // Checked first, so the indices packed while decoding always fit above the status byte.
+    if counts.iter().map(|c| c.sysex).sum::<usize>() > 1 << 24 {
+        return Err(LoadError::Invalid("too many SysEx messages".to_string()));
+    }
+
+    if counts.iter().all(|c| c.last_tick <= u32::MAX as u64) {
+        load_counted::<u32>(&smf, &counts, playback_speed, progress)
+    } else {
+        load_counted::<u64>(&smf, &counts, playback_speed, progress)
+    }
+}
+
+fn load_counted<T: Tick>(smf: &Smf, counts: &[TrackCounts], playback_speed: f64, progress: &LoadProgress) -> Result<Timeline, LoadError> {
+    let len = counts.iter().map(|c| c.events).sum();
+    let mut events = Events::<T>::zeroed(len);
+
+    // Every track decodes into its own part of one buffer, in file order.
+    let mut parts = Vec::with_capacity(counts.len());
+    let mut bounds = vec![0];
+    let (mut ticks, mut words) = (&mut events.ticks[..], &mut events.words[..]);
+    let mut first_sysex = 0;
+    for (track, track_counts) in smf.tracks.iter().zip(counts) {
+        let (track_ticks, rest) = mem::take(&mut ticks).split_at_mut(track_counts.events);
+        ticks = rest;
+        let (track_words, rest) = mem::take(&mut words).split_at_mut(track_counts.events);
+        words = rest;
+        parts.push((*track, first_sysex, track_ticks, track_words));
+        first_sysex += track_counts.sysex;
+        bounds.push(bounds.last().unwrap() + track_counts.events);
+    }
+
+    progress.start(Phase::Parsing, smf.tracks.iter().map(|t| t.len() as u64).sum());
+    let sysex: Vec<&[u8]> = parts
+        .into_par_iter()
+        .map(|(track, first_sysex, ticks, words)| decode_track(track, first_sysex, ticks, words, progress))
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
+
+    // Empty tracks don't need merging.
+    bounds.dedup();
+    progress.start(Phase::Merging, merge_work(bounds.len().saturating_sub(1), len));
+    let merged = merge_runs(events, bounds, progress)?;
+
+    progress.start(Phase::Collecting, merged.len() as u64);
+
+    // Tempo events don't end up in the timeline, so this is a little more than needed.
+    let mut timeline = Timeline::with_capacity(merged.len());
+    let mut clock = Clock::new(smf.division, playback_speed);
+    let mut last_time = 0.0;
+    let mut port = 0;
+
+    for (i, (&tick, &word)) in merged.ticks.iter().zip(merged.words.iter()).enumerate() {
+        clock.advance(tick.to_u64());
+        match word & 0xFF {
+            WORD_TEMPO => clock.set_tempo(word >> 8),
+            WORD_SYSEX => {
+                timeline.push_sysex(clock.time - last_time, sysex[(word >> 8) as usize]);
+                last_time = clock.time;
+            }
+            _ => {
+                if (word >> 24) as u8 != port {
+                    port = (word >> 24) as u8;
+                    timeline.push_port(clock.time - last_time, port);
+                    last_time = clock.time;
+                }
+                timeline.push_short(clock.time - last_time, word & 0xFF_FFFF);
+                last_time = clock.time;
+            }
+        }
+
+        if i as u64 & (REPORT_INTERVAL - 1) == 0 {
+            if progress.is_cancelled() {
+                return Err(LoadError::Cancelled);
+            }
+            progress.set(i as u64);
+        }
+    }
+    timeline.shrink_to_fit();
+    progress.set(merged.len() as u64);
+
+    Ok(timeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Events as `(tick, word)`, to compare.
+    type Pairs = Vec<(u32, u32)>;
+
+    /// Sorted runs of pseudo random ticks from `0..range`, so a small range gives many ties.
+    /// Each word is its track and position in the track, to tell equal ticks apart.
+    fn runs(lengths: &[usize], range: u64) -> (Events<u32>, Vec<usize>) {
+        let mut events = Events::default();
+        let mut bounds = vec![0];
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        for (track, &len) in lengths.iter().enumerate() {
+            let mut ticks: Vec<u32> = (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    (seed % range) as u32
+                })
+                .collect();
+            ticks.sort_unstable();
+            events.ticks.extend(ticks);
+            events.words.extend((0..len as u32).map(|i| (track as u32) << 24 | i));
+            bounds.push(events.len());
+        }
+        (events, bounds)
+    }
+
+    /// What merging the runs one after another with a stable sort gives.
+    fn sequential(events: &Events<u32>) -> Pairs {
+        let mut expected: Vec<_> = events.ticks.iter().copied().zip(events.words.iter().copied()).collect();
+        expected.sort_by_key(|&(tick, _)| tick);
+        expected
+    }
+
+    fn merge(lengths: &[usize], range: u64) -> (Pairs, Pairs) {
+        let (events, bounds) = runs(lengths, range);
+        let expected = sequential(&events);
+        let merged = merge_runs(events, bounds, &LoadProgress::new()).ok().unwrap();
+        (merged.ticks.into_iter().zip(merged.words).collect(), expected)
+    }
+
+    #[test]
+    fn ties_keep_the_track_order() {
+        let (merged, expected) = merge(&[500, 300, 700, 100], 10);
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn merges_odd_numbers_of_runs() {
+        for count in [3, 5, 7, 9] {
+            let lengths: Vec<usize> = (0..count).map(|i| 50 + i * 37).collect();
+            let (merged, expected) = merge(&lengths, 100);
+            assert_eq!(merged, expected, "{} runs", count);
+        }
+    }
+
+    #[test]
+    fn skips_empty_tracks() {
+        let (merged, expected) = merge(&[0, 200, 0, 0, 150, 0], 50);
+        assert_eq!(merged, expected);
+        let (merged, expected) = merge(&[0, 0, 0], 50);
+        assert!(merged.is_empty() && expected.is_empty());
+    }
+
+    #[test]
+    fn leaves_a_single_track_alone() {
+        let (merged, expected) = merge(&[1000], 20);
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn parallel_merges_match_sequential_ones() {
+        // Long enough for merge_into to split its runs, with lots of ties across the split points.
+        let (merged, expected) = merge(&[MIN_PARALLEL_MERGE * 3, MIN_PARALLEL_MERGE, 5000, MIN_PARALLEL_MERGE * 2, 1], 1000);
+        assert_eq!(merged, expected);
+    }
+}
@@ -1,10 +1,3 @@
-use midi_toolkit::{
-    events::{Event, MIDIEvent},
-    io::MIDIFile,
-    pipe,
-    sequence::{event::merge_events_array, to_vec, unwrap_items, event::cancel_tempo_events, TimeCaster, event::scale_event_time}
-};
-
 use std::env;
 use std::{thread, time};
 use std::io::{Write,stdout};
@@ -19,9 +12,34 @@ use crossterm::{QueueableCommand, cursor};
 use crossterm::event;
 use crossterm::event::{KeyEvent, KeyCode, read};
 
-use kdmapi::KDMAPI;
-
-use wfd;
+// First, so the modules below can use its `say!`.
+#[macro_use]
+mod console;
+mod cache;
+mod compression;
+mod loader;
+mod osc;
+mod output;
+mod progress;
+mod render;
+mod rmid;
+mod seek;
+mod smf;
+mod stream;
+mod tap;
+mod timeline;
+mod transport;
+
+use loader::{LoadError, MergedEvents};
+use osc::OscSender;
+use output::{EventTime, OutputBackend};
+use progress::LoadProgress;
+use render::{RenderOutput, SoundFont};
+use seek::{PlayState, Playhead, SeekIndex};
+use smf::{Smf, TrackEvent};
+use tap::EventTap;
+use timeline::Message;
+use transport::Transport;
 
 #[cfg(windows)]
 pub fn enable_virtual_terminal_processing() {
@@ -44,6 +62,11 @@ pub fn write_text(stdout: &mut std::io::Stdout, x: u16, y: u16, text: &str) {
     stdout.flush().ok();
 }
 
+/// Draws the current speed in the top left corner.
+pub fn write_speed(speed: f64) {
+    write_text(&mut stdout(), 0, 0, &format!("\x1b[38;2;0;255;0mSpeed: {:.2}x\x1b[0m", speed));
+}
+
 pub fn set_palette(color_type: i32, note_shades_b: &mut Vec<&str>, note_shades_w: &mut Vec<&str>) {
     match color_type%3 {
         0 => {
@@ -169,13 +192,215 @@ pub fn set_palette(color_type: i32, note_shades_b: &mut Vec<&str>, note_shades_w
     }
 }
 
+/// Shifts the key of note on/off messages by `transpose_value` semitones.
+pub fn transpose_message(serialized: u32, transpose_value: i32) -> u32 {
+    if (transpose_value > 0 || transpose_value < 0) && (serialized & 0xf0 == 0x80 || serialized & 0xf0 == 0x90) {
+        (serialized & 0xff00ff) + ((serialized & 0x00ff00) + ((transpose_value as u32) << 8)) as u32
+    } else {
+        serialized
+    }
+}
+
+/// Parses a time given as seconds (`90`, `12.5`) or minutes and seconds (`1:30`).
+pub fn parse_time(text: &str) -> Option<f64> {
+    let seconds = match text.find(':') {
+        Some(colon) => text[..colon].parse::<u32>().ok()? as f64 * 60.0 + text[colon + 1..].parse::<f64>().ok()?,
+        None => text.parse::<f64>().ok()?,
+    };
+    if seconds >= 0.0 { Some(seconds) } else { None }
+}
+
+/// Options that change what is played, read the same way for playing and rendering.
+struct PlaybackOptions {
+    transpose_value: i32,
+    playback_speed: f64,
+}
+
+fn parse_playback_options(args: &[String]) -> PlaybackOptions {
+    let mut options = PlaybackOptions {
+        transpose_value: 0,
+        playback_speed: 1.0,
+    };
+
+    if args.contains(&"-transpose".to_string()) {
+        options.transpose_value = args[args.iter().position(|r| r == "-transpose").unwrap()+1].parse::<i32>().unwrap();
+        if options.transpose_value < 0 {
+            say!("\x1b[38;2;255;255;0mTranspose value is below 0, defaulting to no transpose...\x1b[0m");
+            options.transpose_value = 0;
+        }
+    }
+
+    if args.contains(&"-playbackSpeed".to_string()) {
+        options.playback_speed = args[args.iter().position(|r| r == "-playbackSpeed").unwrap()+1].parse::<f64>().unwrap();
+    }
+
+    options
+}
+
+/// Shows the title and artist of RMID files under the path in the main menu.
+fn write_midi_info(s: &mut std::io::Stdout, path: &str) {
+    s.queue(cursor::MoveTo(0,3)).ok();
+    s.queue(terminal::Clear(ClearType::CurrentLine)).ok();
+    // Reading stdin here would take the MIDI away from the loader.
+    if path == "-" {
+        return;
+    }
+    if let Ok(Some(info)) = rmid::read_info(path) {
+        let mut line = String::new();
+        if let Some(title) = info.title {
+            line.push_str(&format!("\x1b[38;2;0;255;0mTitle\x1b[0m: {}  ", title));
+        }
+        if let Some(artist) = info.artist {
+            line.push_str(&format!("\x1b[38;2;0;255;0mArtist\x1b[0m: {}", artist));
+        }
+        s.write(line.as_bytes()).ok();
+    }
+}
+
+/// Asks for a MIDI file with the system file dialog.
+#[cfg(windows)]
+fn choose_midi_file() -> Option<String> {
+    let params = wfd::DialogParams {
+        title: "Choose a MIDI file.",
+        file_types: vec![("MIDI Files","*.mid;*.rmi;*.mid.gz;*.mid.xz;*.mid.zst"), ("All Files","*.*")],
+        ..Default::default()
+    };
+    let result = wfd::open_dialog(params).ok()?;
+    result.selected_file_path.to_str().map(|p| p.to_string())
+}
+
+/// Asks for the path of a MIDI file under the main menu, there's no file dialog outside Windows.
+#[cfg(not(windows))]
+fn choose_midi_file() -> Option<String> {
+    let mut s = stdout();
+    // Typed like a normal line, with echo and backspace.
+    disable_raw_mode().unwrap();
+    s.queue(cursor::SavePosition).ok();
+    s.queue(cursor::MoveTo(0,13)).ok();
+    s.queue(terminal::Clear(ClearType::CurrentLine)).ok();
+    s.write_all("\x1b[38;2;0;255;0mMIDI path\x1b[0m: ".as_bytes()).ok();
+    s.flush().ok();
+
+    let mut path = String::new();
+    let read = std::io::stdin().read_line(&mut path);
+
+    s.queue(cursor::MoveTo(0,13)).ok();
+    s.queue(terminal::Clear(ClearType::CurrentLine)).ok();
+    s.queue(cursor::RestorePosition).ok();
+    s.flush().ok();
+    enable_raw_mode().unwrap();
+
+    let path = path.trim();
+    if read.is_err() || path.is_empty() {
+        None
+    } else {
+        Some(path.to_string())
+    }
+}
+
+/// Renders the MIDI to a WAV file through the built-in SoundFont synth, as fast as possible.
+fn render_mode(args: &[String]) {
+    let wav_path = args[args.iter().position(|r| r == "-render").unwrap()+1].clone();
+
+    if !args.contains(&"-soundfont".to_string()) {
+        println!("\x1b[38;2;255;32;32mRendering needs a SoundFont, pass one with -soundfont <path.sf2>.\x1b[0m");
+        std::process::exit(1);
+    }
+    let sf_path = &args[args.iter().position(|r| r == "-soundfont").unwrap()+1];
+
+    let PlaybackOptions { transpose_value, playback_speed } = parse_playback_options(args);
+    let mut max_voices = 1000;
+
+    if args.contains(&"-voices".to_string()) {
+        max_voices = args[args.iter().position(|r| r == "-voices").unwrap()+1].parse::<usize>().unwrap();
+    }
+
+    println!("Loading SoundFont...");
+    let soundfont = match SoundFont::open(sf_path) {
+        Ok(sf) => sf,
+        Err(err) => {
+            println!("\x1b[38;2;255;32;32mCould not load SoundFont: {}\x1b[0m", err);
+            std::process::exit(1);
+        }
+    };
+
+    println!("Loading MIDI...");
+    let midi_bytes = match loader::read_file(&args[1], &LoadProgress::new()) {
+        Ok(b) => b,
+        Err(LoadError::Invalid(err)) => {
+            println!("\x1b[38;2;255;32;32mCould not open '{}': {}\x1b[0m", args[1], err);
+            std::process::exit(1);
+        }
+        // Nothing cancels it without a progress display, but a render that didn't finish isn't a success.
+        Err(LoadError::Cancelled) => {
+            println!("\x1b[38;2;255;32;32mLoading '{}' was cancelled.\x1b[0m", args[1]);
+            std::process::exit(1);
+        }
+    };
+    let smf = match Smf::parse(&midi_bytes) {
+        Ok(s) => s,
+        Err(err) => {
+            println!("\x1b[38;2;255;32;32mCould not load '{}': {}\x1b[0m", args[1], err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut output = RenderOutput::new(&wav_path, soundfont, max_voices);
+    if let Err(err) = output.open() {
+        println!("\x1b[38;2;255;32;32mCould not create {}: {}\x1b[0m", wav_path, err);
+        std::process::exit(1);
+    }
+
+    println!("Rendering to {}...", wav_path);
+    let started = Instant::now();
+    let mut atime = 0.0;
+
+    for e in MergedEvents::new(&smf, playback_speed) {
+        if e.delta != 0.0 {
+            atime += e.delta;
+            output.set_time(EventTime { scheduled: atime, actual: atime });
+        }
+
+        match e.event {
+            TrackEvent::Short(serialized) => output.send_short(transpose_message(serialized, transpose_value)),
+            TrackEvent::SysEx(sysex) => {
+                let mut data = Vec::with_capacity(sysex.len() + 1);
+                data.push(0xF0);
+                data.extend_from_slice(sysex);
+                output.send_sysex(&data);
+            }
+            TrackEvent::Port(port) => output.set_port(port),
+            TrackEvent::Tempo(_) => {}
+        }
+    }
+
+    output.close();
+
+    let elapsed = started.elapsed().as_secs_f64();
+    println!("Rendered {:.1}s of audio in {:.1}s ({:.1}x realtime)", output.rendered_seconds(), elapsed, output.rendered_seconds() / elapsed.max(0.001));
+}
+
 fn main() {
     #[cfg(windows)]
     enable_virtual_terminal_processing();
 
     let mut args: Vec<String> = env::args().collect();
 
-    let is_main_menu = Arc::new(Mutex::new(true));
+    if args.contains(&"-render".to_string()) {
+        render_mode(&args);
+        return;
+    }
+
+    // Raw MIDI or tapped events written to stdout can't share it with the menu and the visualizer.
+    let headless = args.iter().position(|r| r == "-output")
+        .and_then(|i| args.get(i + 1))
+        .is_some_and(|spec| output::writes_stdout(spec))
+        || args.iter().position(|r| r == "-eventTap")
+            .and_then(|i| args.get(i + 1))
+            .is_some_and(|path| path == "-");
+    console::set_headless(headless);
+
+    let is_main_menu = Arc::new(Mutex::new(!headless));
 
     let mm = Arc::clone(&is_main_menu);
 
@@ -207,37 +432,40 @@ fn main() {
     set_palette(color_type, &mut note_shades_b, &mut note_shades_w);
 
     let mut s = stdout();
-    s.queue(terminal::Clear(ClearType::All)).ok();
-    s.queue(cursor::SavePosition).ok();
-    s.queue(cursor::MoveTo(38,0)).ok();
-    s.write("\x1b[38;2;0;255;255mv0.3b\x1b[0m".as_bytes()).ok();
-    s.queue(cursor::RestorePosition).ok();
-    s.queue(cursor::MoveTo(0,0)).ok();
-    s.write("Welcome to \x1b[38;2;0;255;0mUniMIDI\x1b[0m.".as_bytes()).ok();
-    s.queue(cursor::MoveTo(0,2)).ok();
-    s.write(format!("\x1b[38;2;0;255;0mCurrent MIDI path\x1b[0m: {}",&args[1]).as_bytes()).ok();
-    s.queue(cursor::MoveTo(15,4)).ok();
-    s.write(format!("Channel colors:\n0: {} 1: {} 2:  {}  3: {}  4: {}  5: {}  6: {}  7: {}\n8: {} 9: {} 10: {} 11: {} 12: {} 13: {} 14: {} 15: {}",
-note_shades_w[0],
-note_shades_w[1],
-note_shades_w[2],
-note_shades_w[3],
-note_shades_w[4],
-note_shades_w[5],
-note_shades_w[6],
-note_shades_w[7],
-note_shades_w[8],
-note_shades_w[9],
-note_shades_w[10],
-note_shades_w[11],
-note_shades_w[12],
-note_shades_w[13],
-note_shades_w[14],
-note_shades_w[15]
-).as_bytes()).ok();
-    s.queue(cursor::MoveTo(0,8)).ok();
-    s.write("\x1b[1mContinue ..... [\x1b[38;2;0;255;0mAny key\x1b[0m]\n\x1b[1mChange MIDI .. [\x1b[38;2;0;255;0mM\x1b[0m]\n\x1b[1mChange palette [\x1b[38;2;0;255;0m←/→\x1b[0m]\n\x1b[1mHelp ......... [\x1b[38;2;0;255;0mH\x1b[0m]\n\x1b[1mQuit ......... [\x1b[38;2;0;255;0mEsc\x1b[0m]".as_bytes()).ok();
-    s.flush().ok();
+    if !headless {
+        s.queue(terminal::Clear(ClearType::All)).ok();
+        s.queue(cursor::SavePosition).ok();
+        s.queue(cursor::MoveTo(38,0)).ok();
+        s.write("\x1b[38;2;0;255;255mv0.3b\x1b[0m".as_bytes()).ok();
+        s.queue(cursor::RestorePosition).ok();
+        s.queue(cursor::MoveTo(0,0)).ok();
+        s.write("Welcome to \x1b[38;2;0;255;0mUniMIDI\x1b[0m.".as_bytes()).ok();
+        s.queue(cursor::MoveTo(0,2)).ok();
+        s.write(format!("\x1b[38;2;0;255;0mCurrent MIDI path\x1b[0m: {}",&args[1]).as_bytes()).ok();
+        write_midi_info(&mut s, &args[1]);
+        s.queue(cursor::MoveTo(15,4)).ok();
+        s.write(format!("Channel colors:\n0: {} 1: {} 2:  {}  3: {}  4: {}  5: {}  6: {}  7: {}\n8: {} 9: {} 10: {} 11: {} 12: {} 13: {} 14: {} 15: {}",
+    note_shades_w[0],
+    note_shades_w[1],
+    note_shades_w[2],
+    note_shades_w[3],
+    note_shades_w[4],
+    note_shades_w[5],
+    note_shades_w[6],
+    note_shades_w[7],
+    note_shades_w[8],
+    note_shades_w[9],
+    note_shades_w[10],
+    note_shades_w[11],
+    note_shades_w[12],
+    note_shades_w[13],
+    note_shades_w[14],
+    note_shades_w[15]
+    ).as_bytes()).ok();
+        s.queue(cursor::MoveTo(0,8)).ok();
+        s.write("\x1b[1mContinue ..... [\x1b[38;2;0;255;0mAny key\x1b[0m]\n\x1b[1mChange MIDI .. [\x1b[38;2;0;255;0mM\x1b[0m]\n\x1b[1mChange palette [\x1b[38;2;0;255;0m←/→\x1b[0m]\n\x1b[1mHelp ......... [\x1b[38;2;0;255;0mH\x1b[0m]\n\x1b[1mQuit ......... [\x1b[38;2;0;255;0mEsc\x1b[0m]".as_bytes()).ok();
+        s.flush().ok();
+    }
 
     enable_raw_mode().unwrap();
 
@@ -272,16 +500,9 @@ note_shades_w[15]
                 code: KeyCode::Char('m'),
                 modifiers: _no_modifiers,
             }) => {
-                let params = wfd::DialogParams {
-                    title: "Choose a MIDI file.",
-                    file_types: vec![("MIDI Files","*.mid")],
-                    ..Default::default()
-                };
-                let result = wfd::open_dialog(params).unwrap();
-                let path = result.selected_file_path.to_str();
-                match path {
+                match choose_midi_file() {
                     Some(p) => {
-                        args[1] = p.to_string();
+                        args[1] = p;
                     },
                     None => {
                         println!("\x1b[38;2;255;255;0mNo path specified, using intialized path.\x1b[0m");
@@ -291,6 +512,7 @@ note_shades_w[15]
                 s.queue(cursor::MoveTo(0,2)).ok();
                 s.queue(terminal::Clear(ClearType::CurrentLine)).ok();
                 s.write(format!("\x1b[38;2;0;255;0mCurrent MIDI path\x1b[0m: {}",&args[1]).as_bytes()).ok();
+                write_midi_info(&mut s, &args[1]);
                 s.queue(cursor::RestorePosition).ok();
                 s.flush().ok();
             },
@@ -359,7 +581,7 @@ note_shades_w[15]
             }) => {
                 is_help = !is_help;
                 if is_help {
-                    write_text(&mut s, 0, 14, "\x1b[4m\x1b[38;2;0;255;0mHelp:\x1b[0m \n→ - Skip ahead by 3 seconds\n p - Pause");
+                    write_text(&mut s, 0, 14, "\x1b[4m\x1b[38;2;0;255;0mHelp:\x1b[0m \n→ - Skip ahead by 3 seconds\n← - Go back 3 seconds\n0-9 - Jump to 0% - 90% of the song\n↑/↓ - Speed up / slow down\n r - Reset the speed\n p - Pause");
                 } else {
                     s.queue(cursor::SavePosition).ok();
                     s.queue(terminal::Clear(ClearType::FromCursorDown)).ok();
@@ -379,40 +601,27 @@ note_shades_w[15]
 
     mm_thread.join().unwrap();
 
-    s.queue(terminal::Clear(ClearType::All)).ok();
-    s.queue(cursor::MoveTo(0,0)).ok();
-    s.flush().ok();
-    println!("Loading MIDI...");
-
-    let file = MIDIFile::open_in_ram(&args[1], None).unwrap();
-    let file2 = MIDIFile::open_in_ram(&args[1], None).unwrap();
-    let ppq = file.ppq();
-    let ppq2 = file2.ppq();
+    if !headless {
+        s.queue(terminal::Clear(ClearType::All)).ok();
+        s.queue(cursor::MoveTo(0,0)).ok();
+        s.flush().ok();
+    }
+    say!("Loading MIDI...");
 
-    let mut transpose_value: i32 = 0;
-    let mut playback_speed: f64 = 1.0;
+    let PlaybackOptions { transpose_value, playback_speed } = parse_playback_options(&args);
     let mut randomize_colors = false;
     let mut barf_mode = false;
     let mut allow_black_notes = true;
     let mut note_size = 5;
     let mut experimental_overlaps = false;
+    let mut stream_events = false;
+    let mut use_cache = true;
 
     let mut use_colors = true;
+    let mut output_name = output::default_backend().map(|name| name.to_string());
 
     let mut color_index = [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15];
    
-    if args.contains(&"-transpose".to_string()) {
-        transpose_value = args[args.iter().position(|r| r == "-transpose").unwrap()+1].parse::<i32>().unwrap();
-        if transpose_value < 0 {
-            println!("\x1b[38;2;255;255;0mTranspose value is below 0, defaulting to no transpose...\x1b[0m");
-            transpose_value = 0;
-        }
-    }
-
-    if args.contains(&"-playbackSpeed".to_string()) {
-        playback_speed = args[args.iter().position(|r| r == "-playbackSpeed").unwrap()+1].parse::<f64>().unwrap();
-    }
-
     if args.contains(&"-randomizeColors".to_string()) {
         randomize_colors = true;
     }
@@ -431,57 +640,199 @@ note_shades_w[15]
 
     if args.contains(&"-experimentalOverlaps".to_string()) {
         experimental_overlaps = true;
-        println!("\x1b[38;2;255;32;32mWARNING: UniMIDI will run slower with 'experimentalOverlaps'.\x1b[0m");
+        say!("\x1b[38;2;255;32;32mWARNING: UniMIDI will run slower with 'experimentalOverlaps'.\x1b[0m");
+    }
+
+    if args.contains(&"-stream".to_string()) {
+        stream_events = true;
+    }
+
+    if args.contains(&"-noCache".to_string()) {
+        use_cache = false;
     }
 
     if args.contains(&"-noColors".to_string()) {
         use_colors = false;
     }
 
+    if args.contains(&"-output".to_string()) {
+        output_name = Some(args[args.iter().position(|r| r == "-output").unwrap()+1].clone());
+    }
+
+    let output_name = match output_name {
+        Some(name) => name,
+        None => {
+            disable_raw_mode().unwrap();
+            say!("\x1b[38;2;255;32;32mThere's no default output on this platform, choose one with -output (e.g. -output dll:/usr/lib/libOmniMIDI.so).\x1b[0m");
+            std::process::exit(1);
+        }
+    };
+
+    let mut start_time: Option<f64> = None;
+    if args.contains(&"-start".to_string()) {
+        let start_arg = &args[args.iter().position(|r| r == "-start").unwrap()+1];
+        match parse_time(start_arg) {
+            Some(t) => start_time = Some(t),
+            None => say!("\x1b[38;2;255;255;0mInvalid start time '{}', starting from the beginning...\x1b[0m", start_arg),
+        }
+    }
+
+    let mut route_spec: Option<String> = None;
+    if args.contains(&"-route".to_string()) {
+        route_spec = Some(args[args.iter().position(|r| r == "-route").unwrap()+1].clone());
+    }
+
+    let mut output = match output::create_backend(&output_name, route_spec.as_deref()) {
+        Ok(o) => o,
+        Err(err) => match output::default_backend() {
+            Some(default) if default != output_name => {
+                say!("\x1b[38;2;255;255;0mInvalid output: {}, defaulting to {}...\x1b[0m", err, default);
+                output::create_backend(default, None).unwrap()
+            }
+            _ => {
+                disable_raw_mode().unwrap();
+                say!("\x1b[38;2;255;32;32mInvalid output: {}\x1b[0m", err);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut event_tap: Option<EventTap> = None;
+    if args.contains(&"-eventTap".to_string()) {
+        let tap_path = &args[args.iter().position(|r| r == "-eventTap").unwrap()+1];
+        match EventTap::open(tap_path) {
+            Ok(t) => event_tap = Some(t),
+            Err(err) => say!("\x1b[38;2;255;255;0mCould not open event tap '{}': {}, continuing without it...\x1b[0m", tap_path, err),
+        }
+    }
+
+    let mut osc_sender: Option<OscSender> = None;
+    if args.contains(&"-osc".to_string()) {
+        let osc_target = &args[args.iter().position(|r| r == "-osc").unwrap()+1];
+        match OscSender::connect(osc_target) {
+            Ok(o) => osc_sender = Some(o),
+            Err(err) => say!("\x1b[38;2;255;255;0mCould not send OSC to '{}': {}, continuing without it...\x1b[0m", osc_target, err),
+        }
+    }
+
     if randomize_colors {
         let mut rng = thread_rng();
         color_index.shuffle(&mut rng);
     }
 
-    println!("Merging events...");
-
-    let merged = pipe!(
-        file.iter_all_tracks()
-        |>to_vec()
-        |>merge_events_array()
-        |>TimeCaster::<f64>::cast_event_delta()
-        |>cancel_tempo_events((250000.0 / playback_speed) as u32)
-        |>scale_event_time(1.0 / ppq as f64)
-        |>unwrap_items()
-    );
-
-    println!("Preparing audio...");
+    // Only known up front when the whole song is loaded.
+    let mut song_length: Option<f64> = None;
+    let mut seek_index: Option<Arc<SeekIndex>> = None;
+
+    // Both the audio and the visual thread get every event, in chunks.
+    let mut event_receivers = if stream_events {
+        say!("Streaming events...");
+
+        // Only shows anything if the file has to be decompressed first.
+        let progress = Arc::new(LoadProgress::new());
+        let display = progress::show(Arc::clone(&progress));
+        let mapped = loader::map_file(&args[1], &progress);
+        display.finish();
+
+        let midi_map = match mapped {
+            Ok(m) => m,
+            Err(LoadError::Cancelled) => {
+                disable_raw_mode().unwrap();
+                say!("Loading cancelled.");
+                std::process::exit(0);
+            }
+            Err(LoadError::Invalid(err)) => {
+                disable_raw_mode().unwrap();
+                say!("\x1b[38;2;255;32;32mCould not open '{}': {}\x1b[0m", args[1], err);
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = Smf::parse(&midi_map) {
+            disable_raw_mode().unwrap();
+            say!("\x1b[38;2;255;32;32mCould not load '{}': {}\x1b[0m", args[1], err);
+            std::process::exit(1);
+        }
 
-    let amerged = pipe!(
-        file2.iter_all_tracks()
-        |>to_vec()
-        |>merge_events_array()
-        |>TimeCaster::<f64>::cast_event_delta()
-        |>cancel_tempo_events((250000.0 / playback_speed) as u32)
-        |>scale_event_time(1.0 / ppq2 as f64)
-        |>unwrap_items()
-    );
+        let (mut sender, receivers) = stream::chunked(2);
+
+        // Decodes and merges the tracks as playback goes, a few seconds of the song ahead.
+        // Streaming is about starting right away, so it skips the event cache.
+        thread::spawn(move || {
+            let smf = match Smf::parse(&midi_map) {
+                Ok(smf) => smf,
+                Err(err) => return sender.fail(err),
+            };
+            for e in MergedEvents::new(&smf, playback_speed) {
+                if !sender.push_event(e.delta, e.event) {
+                    break;
+                }
+            }
+        });
+
+        receivers
+    } else {
+        let progress = Arc::new(LoadProgress::new());
+        let display = progress::show(Arc::clone(&progress));
+
+        // Looked up before reading the file, so a cached song never goes through RAM.
+        let cache_key = if use_cache { cache::CacheKey::for_file(&args[1], playback_speed) } else { None };
+        let loaded = match cache_key.and_then(cache::load) {
+            Some((timeline, index)) => Ok((timeline, None, index)),
+            None => loader::read_file(&args[1], &progress)
+                .and_then(|bytes| loader::load(&bytes, playback_speed, &progress))
+                .and_then(|timeline| {
+                    SeekIndex::build(&timeline, &progress).map(|index| (timeline, cache_key, index))
+                }),
+        };
+        display.finish();
+
+        let (timeline, uncached_key, index) = match loaded {
+            Ok(l) => l,
+            Err(LoadError::Cancelled) => {
+                disable_raw_mode().unwrap();
+                say!("Loading cancelled.");
+                std::process::exit(0);
+            }
+            Err(LoadError::Invalid(err)) => {
+                disable_raw_mode().unwrap();
+                say!("\x1b[38;2;255;32;32mCould not load '{}': {}\x1b[0m", args[1], err);
+                std::process::exit(1);
+            }
+        };
 
+        match uncached_key {
+            Some(key) => {
+                say!("Writing event cache...");
+                if let Err(err) = cache::save(key, &timeline, &index) {
+                    say!("\x1b[38;2;255;255;0mCould not write the event cache: {}\x1b[0m", err);
+                }
+            }
+            None if cache_key.is_some() => say!("Loaded {} events from the cache", timeline.len()),
+            None => {}
+        }
 
-    println!("Loading events to RAM...");
+        // From the index, a cached timeline's deltas haven't been read from disk.
+        song_length = Some(index.duration());
+        seek_index = Some(Arc::new(index));
 
-    let merged = to_vec(merged);
+        // Parsed and merged once, then shared by both threads.
+        stream::preloaded(timeline, 2)
+    };
 
-    println!("Finalizing audio...");
-    let amerged = to_vec(amerged);
+    let visual_events = event_receivers.pop().unwrap();
+    let audio_events = event_receivers.pop().unwrap();
 
-    println!("Initializing visualizer...");
+    say!("Initializing visualizer...");
 
     let mut num_overlaps: [i32; 128] = [0; 128];
 
     let keyboard_string: Arc<Mutex<[&str]>> = Arc::new(Mutex::new([" "; 128]));
 
-    let kdmapi = KDMAPI.open_stream();
+    if let Err(err) = output.open() {
+        disable_raw_mode().unwrap();
+        say!("\x1b[38;2;255;32;32mCould not open output '{}': {}\x1b[0m", output_name, err);
+        std::process::exit(1);
+    }
 
     //let crossterm = Crossterm::new();
 
@@ -490,56 +841,86 @@ note_shades_w[15]
 
     let now = Instant::now();
 
-    let mut time = 0.0;
-    let mut atime = 0.0;
-
     let midi_ended = Arc::new(Mutex::new(false));
-    let skip_length = Arc::new(Mutex::new(0.0));
-    let playback_offset = Arc::new(Mutex::new(0.0));
-    let paused = Arc::new(Mutex::new(false));
+    // Everything plays, pauses and seeks by this one clock.
+    let transport = Arc::new(Transport::new());
+    if let Some(start) = start_time {
+        transport.seek(start);
+    }
 
     let keyboard_thread = Arc::clone(&keyboard_string);
     let midi_end = Arc::clone(&midi_ended);
-    let skip_len = Arc::clone(&skip_length);
-    let play_offset = Arc::clone(&playback_offset);
-    let paused_midi = Arc::clone(&paused);
+    let audio_transport = Arc::clone(&transport);
+    let audio_seek_index = seek_index.clone();
 
-    println!("Done!");
+    say!("Done!");
 
     let audio_thread = thread::spawn(move || {
-        for e in amerged {
-            if e.delta() != 0.0 {
-                {
-                    while *paused_midi.lock().unwrap() {
-                        thread::sleep(time::Duration::from_secs_f64(0.1));
-                    }
-                }
+        let mut playhead = Playhead::new(audio_events, audio_seek_index);
+        let mut state = PlayState::new();
+        // Only ports that played something can have notes sounding to silence on a seek.
+        let mut ports_played = 1;
+        // A -start seek is caught up with right away, later ones while waiting.
+        let mut seeks = 0;
+        let mut seeked = audio_transport.seeks() != seeks;
+
+        while let Some(atime) = playhead.next_time() {
+            if !seeked && atime != playhead.time() {
+                output.before_wait(EventTime {
+                    scheduled: atime,
+                    actual: audio_transport.position(),
+                }, audio_transport.rate());
+            }
+            if seeked || (atime != playhead.time() && !audio_transport.wait_until(atime, seeks)) {
+                seeks = audio_transport.seeks();
+                seeked = false;
+                let position = audio_transport.position();
+                playhead.seek(position, &mut state);
+                output.before_wait(EventTime {
+                    scheduled: position,
+                    actual: position,
+                }, audio_transport.rate());
+                output::reset_ports(&mut *output, ports_played, state.port());
+                state.replay(&mut *output);
+                continue;
+            }
 
-                atime += e.delta();
-                let diff = atime - ((now.elapsed().as_secs_f64()+(*play_offset.lock().unwrap()))-(*skip_len.lock().unwrap()));
-                
-                if diff > 0.0 {
-                    spin_sleep::sleep(time::Duration::from_secs_f64(diff));
-                }
+            let e = playhead.advance().unwrap();
+            if e.delta != 0.0 {
+                output.set_time(EventTime {
+                    scheduled: atime,
+                    actual: audio_transport.position(),
+                });
             }
 
-            if let Some(mut serialized) = e.as_u32() {
-                if (transpose_value > 0 || transpose_value < 0) && (serialized & 0xf0 == 0x80 || serialized & 0xf0 == 0x90) {
-                    serialized = (serialized & 0xff00ff) + ((serialized & 0x00ff00) + ((transpose_value as u32) << 8)) as u32;
+            if let Some(message) = &e.message {
+                state.apply(message);
+            }
+            match e.message {
+                Some(Message::Short(serialized)) => output.send_short(transpose_message(serialized, transpose_value)),
+                Some(Message::SysEx(data)) => output.send_sysex(data),
+                Some(Message::Port(port)) => {
+                    ports_played = ports_played.max(port as usize + 1);
+                    output.set_port(port);
                 }
-                kdmapi.send_direct_data(serialized);
+                None => {}
             }
         }
+
+        (output, playhead.error().map(|e| e.to_string()))
     });
 
-    let paused_midi = Arc::clone(&paused);
-    let play_offset = Arc::clone(&playback_offset);
-    let skip_len = Arc::clone(&skip_length);
+    let visual_transport = Arc::clone(&transport);
 
     let thread_1 = thread::spawn(move || {
         let mut keyboard_string = [" "; 128];
-        for e in merged {
-            if e.delta() != 0.0 {
+        let mut playhead = Playhead::new(visual_events, seek_index);
+        let mut state = PlayState::new();
+        let mut seeks = 0;
+        let mut seeked = visual_transport.seeks() != seeks;
+
+        while let Some(time) = playhead.next_time() {
+            if time != playhead.time() && !seeked {
                 {
                     let mut ks = keyboard_thread.lock().unwrap();
                     for i in 0..ks.len() {
@@ -547,33 +928,96 @@ note_shades_w[15]
                     }
                 }
 
-                {
-                    while *paused_midi.lock().unwrap() {
-                        thread::sleep(time::Duration::from_secs_f64(0.1));
-                        let mut skip_l = skip_len.lock().unwrap();
-                        *skip_l += 0.1;
-                    }
+                if let Some(osc) = &mut osc_sender {
+                    osc.update(playhead.time());
                 }
 
-                time += e.delta();
-                let diff = time - ((now.elapsed().as_secs_f64()+(*play_offset.lock().unwrap()))-(*skip_len.lock().unwrap()));
-                
-                if diff > 0.0 {
-                    spin_sleep::sleep(time::Duration::from_secs_f64(diff));
+                // Everything up to now is written out before waiting for the next events.
+                if let Some(tap) = &mut event_tap {
+                    tap.flush();
                 }
 
+                let diff = time - visual_transport.position();
+                seeked = !visual_transport.wait_until(time, seeks);
+
                 if diff > 0.01 && barf_mode {
                     let mut rng = thread_rng();
                     color_index.shuffle(&mut rng);
                 }
             }
 
-            match e {
-                Event::NoteOn(e) => {
-                    let n = (e.key as i32 + (transpose_value as i32)) as u8 % 12;
+            if seeked {
+                seeks = visual_transport.seeks();
+                seeked = false;
+                playhead.seek(visual_transport.position(), &mut state);
+                if let Some(osc) = &mut osc_sender {
+                    osc.release_all();
+                }
+
+                // Show the keys held at the new position, as if it had played there.
+                for key in 0..128 {
+                    let kb_idx = (key+transpose_value as usize)%128;
+                    let n = (key+transpose_value as usize)%12;
+                    let black_note = n == 1 || n == 3 || n == 6 || n == 8 || n == 10;
+                    let n_idx = state.last_channel(key);
+
+                    overlap_index[kb_idx].clear();
+                    overlap_colors[kb_idx].clear();
+                    num_overlaps[kb_idx] = 0;
+                    for channel in 0..16 {
+                        for _ in 0..state.held(channel, key) {
+                            if experimental_overlaps {
+                                overlap_index[kb_idx].push(num_overlaps[kb_idx]);
+                                overlap_colors[kb_idx].push(channel as i32);
+                            }
+                            num_overlaps[kb_idx] += 1;
+                        }
+                        if let Some(osc) = &mut osc_sender {
+                            osc.hold(channel as u8, kb_idx as u8, state.held(channel, key));
+                        }
+                    }
+
+                    keyboard_string[kb_idx] = if num_overlaps[kb_idx] == 0 {
+                        " "
+                    } else if !use_colors {
+                        ["`",".",",","!","#","&","$","@","`",".",",","!","#","&","$","@"][n_idx]
+                    } else if black_note && allow_black_notes {
+                        note_shades_b[n_idx]
+                    } else {
+                        note_shades_w[n_idx]
+                    };
+                }
+
+                if let Some(osc) = &mut osc_sender {
+                    osc.update(visual_transport.position());
+                }
+                continue;
+            }
+
+            let e = playhead.advance().unwrap();
+            if let Some(message) = &e.message {
+                state.apply(message);
+            }
+
+            let serialized = match e.message {
+                Some(Message::Short(serialized)) => serialized,
+                _ => continue,
+            };
+
+            if let Some(tap) = &mut event_tap {
+                tap.write_event(now.elapsed().as_secs_f64(), time, transpose_message(serialized, transpose_value));
+            }
+
+            let channel = (serialized & 0x0F) as u8;
+            let key = ((serialized >> 8) & 0x7F) as u8;
+            let velocity = ((serialized >> 16) & 0x7F) as u8;
+
+            match serialized & 0xF0 {
+                0x90 => {
+                    let n = (key as i32 + (transpose_value as i32)) as u8 % 12;
                     let black_note = n == 1 || n == 3 || n == 6 || n == 8 || n == 10;
-                    let kb_idx = ((e.key+transpose_value as u8)%128) as usize;
-                    let n_idx = (e.channel) as usize;
+                    let kb_idx = ((key+transpose_value as u8)%128) as usize;
+                    let n_idx = (channel) as usize;
 
                     if use_colors {
                         keyboard_string[kb_idx] = note_shades_w[n_idx];
@@ -589,14 +1033,18 @@ note_shades_w[15]
                         overlap_colors[kb_idx].push(n_idx as i32);
                     }
                     num_overlaps[kb_idx] += 1;
+
+                    if let Some(osc) = &mut osc_sender {
+                        osc.note_on(channel, kb_idx as u8, velocity);
+                    }
                 }
-                Event::NoteOff(e) => {
-                    let kb_idx = ((e.key+transpose_value as u8)%128) as usize;
-                    let n = (e.key as i32 + (transpose_value as i32)) as u8 % 12;
+                0x80 => {
+                    let kb_idx = ((key+transpose_value as u8)%128) as usize;
+                    let n = (key as i32 + (transpose_value as i32)) as u8 % 12;
                     let black_note = n == 1 || n == 3 || n == 6 || n == 8 || n == 10;
-                    
+                
                     if experimental_overlaps {
-                        let tmp_pos = overlap_colors[kb_idx].iter().position(|&r| r == (e.channel % 16) as i32).unwrap();
+                        let tmp_pos = overlap_colors[kb_idx].iter().position(|&r| r == (channel % 16) as i32).unwrap();
                         overlap_colors[kb_idx].remove(tmp_pos);
 
                         let mut overlap_colors_len = 0;
@@ -618,58 +1066,103 @@ note_shades_w[15]
                     }
 
                     num_overlaps[kb_idx] -= 1;
-                    if num_overlaps[((e.key+transpose_value as u8)%128) as usize] == 0 {
-                        keyboard_string[((e.key+transpose_value as u8)%128) as usize] = &" ";
+
+                    if let Some(osc) = &mut osc_sender {
+                        osc.note_off(channel, kb_idx as u8);
+                    }
+
+                    if num_overlaps[((key+transpose_value as u8)%128) as usize] == 0 {
+                        keyboard_string[((key+transpose_value as u8)%128) as usize] = &" ";
                     }
                 },
                 _ => {}
             }
         }
 
+        if let Some(tap) = &mut event_tap {
+            tap.flush();
+        }
+
         let mut mid_end = midi_end.lock().unwrap();
         *mid_end = true;
-        std::process::exit(0);
     });
 
     let keyboard_thread = Arc::clone(&keyboard_string);
     let midi_end = Arc::clone(&midi_ended);
-    let paused_midi = Arc::clone(&paused);
+    let render_transport = Arc::clone(&transport);
 
     let thread_2 = thread::spawn(move || {
+        // Nothing to draw on, stdout carries the MIDI data or events.
+        if headless {
+            return;
+        }
         while !(*midi_end.lock().unwrap()) {
             println!("{}", keyboard_thread.lock().unwrap().join(""));
-            thread::sleep(time::Duration::from_millis(((note_size as f64)/playback_speed) as u64));
-            {
-                while *paused_midi.lock().unwrap() {
-                    thread::sleep(time::Duration::from_secs_f64(0.1));
-                }
-            }
+            // Drawn over the newest lines, so it stays in the corner while they scroll.
+            write_speed(playback_speed * render_transport.rate());
+            thread::sleep(time::Duration::from_millis(((note_size as f64)/(playback_speed*render_transport.rate())) as u64));
+            render_transport.wait_while_paused();
         }
     });
 
     let midi_end = Arc::clone(&midi_ended);
-    let paused_midi = Arc::clone(&paused);
-    let play_offset = Arc::clone(&playback_offset);
+    let input_transport = Arc::clone(&transport);
 
     let keyboard_inputs = thread::spawn(move || {
+        // Redraws the speed right away, the visualizer doesn't draw while paused.
+        let set_rate = |rate: f64| {
+            input_transport.set_rate(rate);
+            if !headless {
+                write_speed(playback_speed * rate);
+            }
+        };
         while !(*midi_end.lock().unwrap()) {
             match read().unwrap() {
                 event::Event::Key(KeyEvent {
                     code: KeyCode::Char('p'),
                     modifiers: _no_modifiers,
                 }) => {
-                    {
-                        let mut psd = paused_midi.lock().unwrap();
-                        *psd = !*psd;
-                    }
+                    input_transport.toggle_pause();
                 },
                 event::Event::Key(KeyEvent {
                     code: KeyCode::Right,
                     modifiers: _no_modifiers,
                 }) => {
-                    {
-                        let mut play_offs = play_offset.lock().unwrap();
-                        *play_offs = *play_offs + 3.0;
+                    input_transport.seek(input_transport.position() + 3.0);
+                },
+                // Streamed chunks are gone once they played, so there's no going back.
+                event::Event::Key(KeyEvent {
+                    code: KeyCode::Left,
+                    modifiers: _no_modifiers,
+                }) if !stream_events => {
+                    input_transport.seek(input_transport.position() - 3.0);
+                },
+                // In steps of a tenth, from 0.1x to 4x the speed the song was loaded with.
+                event::Event::Key(KeyEvent {
+                    code: KeyCode::Up,
+                    modifiers: _no_modifiers,
+                }) => {
+                    set_rate(((input_transport.rate() * 10.0).round() + 1.0).min(40.0) / 10.0);
+                },
+                event::Event::Key(KeyEvent {
+                    code: KeyCode::Down,
+                    modifiers: _no_modifiers,
+                }) => {
+                    set_rate(((input_transport.rate() * 10.0).round() - 1.0).max(1.0) / 10.0);
+                },
+                event::Event::Key(KeyEvent {
+                    code: KeyCode::Char('r'),
+                    modifiers: _no_modifiers,
+                }) => {
+                    set_rate(1.0);
+                },
+                event::Event::Key(KeyEvent {
+                    code: KeyCode::Char(digit @ '0'..='9'),
+                    modifiers: _no_modifiers,
+                }) => {
+                    if let Some(length) = song_length {
+                        let tenths = digit.to_digit(10).unwrap() as f64;
+                        input_transport.seek(length * tenths / 10.0);
                     }
                 },
                 _ => (),
@@ -677,10 +1170,18 @@ note_shades_w[15]
         }
     });
 
-    audio_thread.join().unwrap();
+    let (mut output, stream_error) = audio_thread.join().unwrap();
     thread_1.join().unwrap();
     thread_2.join().unwrap();
-    keyboard_inputs.join().unwrap();
 
     disable_raw_mode().unwrap();
+    output.close();
+
+    if let Some(err) = stream_error {
+        say!("\x1b[38;2;255;32;32mPlayback stopped early, could not load the rest of '{}': {}\x1b[0m", args[1], err);
+    }
+
+    // The input thread is still blocked waiting for a key, so don't join it.
+    drop(keyboard_inputs);
+    std::process::exit(0);
 }
@@ -0,0 +1,141 @@
+use std::io;
+use std::net::UdpSocket;
+
+// Keeps bundles well under the usual UDP payload limits.
+const MAX_BUNDLE_SIZE: usize = 8192;
+
+enum Arg {
+    Int(i32),
+    Float(f32),
+}
+
+/// OSC strings and blobs are zero padded to a multiple of 4 bytes.
+fn pad(buf: &mut Vec<u8>) {
+    let padding = (4 - buf.len() % 4) % 4;
+    buf.resize(buf.len() + padding, 0);
+}
+
+fn encode_message(address: &str, args: &[Arg]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(32);
+    buf.extend_from_slice(address.as_bytes());
+    buf.push(0);
+    pad(&mut buf);
+
+    buf.push(b',');
+    for arg in args {
+        buf.push(match arg {
+            Arg::Int(_) => b'i',
+            Arg::Float(_) => b'f',
+        });
+    }
+    buf.push(0);
+    pad(&mut buf);
+
+    for arg in args {
+        match arg {
+            Arg::Int(v) => buf.extend_from_slice(&v.to_be_bytes()),
+            Arg::Float(v) => buf.extend_from_slice(&v.to_be_bytes()),
+        }
+    }
+    buf
+}
+
+/// Sends what the visualizer shows as OSC over UDP:
+///
+/// - `/unimidi/note_on  i:channel i:key i:velocity`
+/// - `/unimidi/note_off i:channel i:key`
+/// - `/unimidi/channel  i:channel i:held notes` (only when it changes)
+/// - `/unimidi/position f:seconds`
+///
+/// Messages are collected and sent as one bundle per visual update.
+pub struct OscSender {
+    socket: UdpSocket,
+    bundle: Vec<u8>,
+    /// How many times each key is held on each channel, to release them all on a seek.
+    keys: [[u16; 128]; 16],
+    held: [i32; 16],
+    sent_held: [i32; 16],
+}
+
+impl OscSender {
+    /// Connects to `target`, given as `host:port`.
+    pub fn connect(target: &str) -> io::Result<OscSender> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(target)?;
+        Ok(OscSender {
+            socket,
+            bundle: Vec::new(),
+            keys: [[0; 128]; 16],
+            held: [0; 16],
+            sent_held: [0; 16],
+        })
+    }
+
+    fn queue(&mut self, address: &str, args: &[Arg]) {
+        let message = encode_message(address, args);
+        if !self.bundle.is_empty() && self.bundle.len() + message.len() + 4 > MAX_BUNDLE_SIZE {
+            self.send_bundle();
+        }
+        if self.bundle.is_empty() {
+            self.bundle.extend_from_slice(b"#bundle\0");
+            // Time tag 1 means "immediately"
+            self.bundle.extend_from_slice(&1u64.to_be_bytes());
+        }
+        self.bundle.extend_from_slice(&(message.len() as i32).to_be_bytes());
+        self.bundle.extend_from_slice(&message);
+    }
+
+    fn send_bundle(&mut self) {
+        if !self.bundle.is_empty() {
+            // Nobody listening is fine, OSC is fire and forget.
+            self.socket.send(&self.bundle).ok();
+            self.bundle.clear();
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        self.keys[(channel % 16) as usize][(key % 128) as usize] += 1;
+        self.held[(channel % 16) as usize] += 1;
+        self.queue("/unimidi/note_on", &[Arg::Int(channel as i32), Arg::Int(key as i32), Arg::Int(velocity as i32)]);
+    }
+
+    pub fn note_off(&mut self, channel: u8, key: u8) {
+        let count = &mut self.keys[(channel % 16) as usize][(key % 128) as usize];
+        *count = count.saturating_sub(1);
+        let held = &mut self.held[(channel % 16) as usize];
+        *held = (*held - 1).max(0);
+        self.queue("/unimidi/note_off", &[Arg::Int(channel as i32), Arg::Int(key as i32)]);
+    }
+
+    /// Sends a note off for every held note, before playback jumps somewhere else.
+    pub fn release_all(&mut self) {
+        for channel in 0..16 {
+            for key in 0..128 {
+                for _ in 0..self.keys[channel][key] {
+                    self.queue("/unimidi/note_off", &[Arg::Int(channel as i32), Arg::Int(key as i32)]);
+                }
+                self.keys[channel][key] = 0;
+            }
+            self.held[channel] = 0;
+        }
+    }
+
+    /// Counts notes that are held where playback jumped to, without sending note ons for
+    /// them. Their channel activity goes out with the next update.
+    pub fn hold(&mut self, channel: u8, key: u8, count: u16) {
+        self.keys[(channel % 16) as usize][(key % 128) as usize] += count;
+        self.held[(channel % 16) as usize] += count as i32;
+    }
+
+    /// Sends everything queued since the last update, along with channel activity and the position.
+    pub fn update(&mut self, position: f64) {
+        for channel in 0..16 {
+            if self.held[channel] != self.sent_held[channel] {
+                self.sent_held[channel] = self.held[channel];
+                self.queue("/unimidi/channel", &[Arg::Int(channel as i32), Arg::Int(self.held[channel])]);
+            }
+        }
+        self.queue("/unimidi/position", &[Arg::Float(position as f32)]);
+        self.send_bundle();
+    }
+}
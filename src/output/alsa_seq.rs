@@ -0,0 +1,87 @@
+use std::ffi::CString;
+
+use alsa::seq::{MidiEvent, PortCap, PortInfo, PortType, Seq};
+use alsa::Direction;
+
+use super::{message_length, OutputBackend};
+
+/// Publishes a readable ALSA sequencer port. Connect it to a synth with `aconnect`.
+pub struct AlsaOutput {
+    client_name: String,
+    seq: Option<Seq>,
+    port: i32,
+    encoder: Option<MidiEvent>,
+}
+
+// The encoder handle isn't marked Send, but it is only ever used by one thread at a time.
+unsafe impl Send for AlsaOutput {}
+
+impl AlsaOutput {
+    pub fn new(client_name: &str) -> AlsaOutput {
+        AlsaOutput {
+            client_name: client_name.to_string(),
+            seq: None,
+            port: 0,
+            encoder: None,
+        }
+    }
+
+    fn send_bytes(&mut self, bytes: &[u8]) {
+        let (seq, encoder) = match (&self.seq, &mut self.encoder) {
+            (Some(s), Some(e)) => (s, e),
+            _ => return,
+        };
+
+        // The encoder is stateful, so a clean reset keeps one bad message from swallowing the next.
+        encoder.reset_encode();
+        if let Ok((_, Some(mut ev))) = encoder.encode(bytes) {
+            ev.set_source(self.port);
+            ev.set_subs();
+            ev.set_direct();
+            seq.event_output_direct(&mut ev).ok();
+        }
+    }
+}
+
+impl OutputBackend for AlsaOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let seq = Seq::open(None, Some(Direction::Playback), false).map_err(|e| format!("could not open the ALSA sequencer: {}", e))?;
+        let name = CString::new(self.client_name.clone()).map_err(|e| e.to_string())?;
+        seq.set_client_name(&name).map_err(|e| e.to_string())?;
+
+        let mut port_info = PortInfo::empty().map_err(|e| e.to_string())?;
+        port_info.set_capability(PortCap::READ | PortCap::SUBS_READ);
+        port_info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+        port_info.set_name(&name);
+        seq.create_port(&port_info).map_err(|e| format!("could not create the ALSA port: {}", e))?;
+
+        // Big enough for the SysEx messages black MIDIs usually carry (GM/GS resets).
+        let encoder = MidiEvent::new(1024).map_err(|e| e.to_string())?;
+        encoder.enable_running_status(false);
+
+        self.port = port_info.get_port();
+        self.seq = Some(seq);
+        self.encoder = Some(encoder);
+
+        say!("Opened ALSA sequencer port {}:{}", self.seq.as_ref().unwrap().client_id().unwrap_or(-1), self.port);
+        Ok(())
+    }
+
+    fn send_short(&mut self, message: u32) {
+        let bytes = message.to_le_bytes();
+        let len = message_length(bytes[0]);
+        self.send_bytes(&bytes[..len]);
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        self.send_bytes(data);
+    }
+
+    fn close(&mut self) {
+        if let Some(seq) = &self.seq {
+            seq.drain_output().ok();
+        }
+        self.encoder = None;
+        self.seq = None;
+    }
+}
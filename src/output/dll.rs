@@ -0,0 +1,87 @@
+use libloading::Library;
+
+use super::{silence_channels, OutputBackend};
+
+type InitializeFn = unsafe extern "system" fn() -> i32;
+type SendDirectDataFn = unsafe extern "system" fn(u32) -> u32;
+type TerminateFn = unsafe extern "system" fn() -> i32;
+type ResetFn = unsafe extern "system" fn();
+
+struct Binds {
+    initialize: InitializeFn,
+    send_direct_data: SendDirectDataFn,
+    terminate: TerminateFn,
+    reset: Option<ResetFn>,
+}
+
+/// Loads any shared library that exports the KDMAPI C functions
+/// (`InitializeKDMAPIStream`, `SendDirectData`, `TerminateKDMAPIStream`).
+pub struct DllOutput {
+    path: String,
+    binds: Option<Binds>,
+    // Declared after `binds` so the function pointers are gone before the library is unloaded.
+    library: Option<Library>,
+}
+
+impl DllOutput {
+    pub fn new(path: &str) -> DllOutput {
+        DllOutput {
+            path: path.to_string(),
+            binds: None,
+            library: None,
+        }
+    }
+}
+
+impl OutputBackend for DllOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let library = Library::new(&self.path).map_err(|e| e.to_string())?;
+
+        let binds = unsafe {
+            let symbol_error = |name: &str, e: std::io::Error| format!("{} doesn't export {}: {}", self.path, name, e);
+            Binds {
+                initialize: *library.get::<InitializeFn>(b"InitializeKDMAPIStream\0").map_err(|e| symbol_error("InitializeKDMAPIStream", e))?,
+                send_direct_data: *library.get::<SendDirectDataFn>(b"SendDirectData\0").map_err(|e| symbol_error("SendDirectData", e))?,
+                terminate: *library.get::<TerminateFn>(b"TerminateKDMAPIStream\0").map_err(|e| symbol_error("TerminateKDMAPIStream", e))?,
+                reset: library.get::<ResetFn>(b"ResetKDMAPIStream\0").ok().map(|s| *s),
+            }
+        };
+
+        if unsafe { (binds.initialize)() } == 0 {
+            return Err(format!("InitializeKDMAPIStream in {} failed", self.path));
+        }
+
+        self.binds = Some(binds);
+        self.library = Some(library);
+        Ok(())
+    }
+
+    fn send_short(&mut self, message: u32) {
+        if let Some(binds) = &self.binds {
+            unsafe {
+                (binds.send_direct_data)(message);
+            }
+        }
+    }
+
+    fn send_sysex(&mut self, _data: &[u8]) {
+        // SendDirectLongData takes a Windows MIDIHDR, which isn't portable, so SysEx is dropped.
+    }
+
+    fn reset(&mut self) {
+        match &self.binds {
+            Some(Binds { reset: Some(reset), .. }) => unsafe { reset() },
+            Some(_) => silence_channels(self),
+            None => {}
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(binds) = self.binds.take() {
+            unsafe {
+                (binds.terminate)();
+            }
+        }
+        self.library = None;
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+
+use super::{EventTime, OutputBackend};
+
+enum Command {
+    SetTime(EventTime),
+    BeforeWait(EventTime, f64),
+    Port(u8),
+    Short(u32),
+    SysEx(Vec<u8>),
+    Reset,
+    Close,
+}
+
+// Commands a backend can fall behind by before its note-ons are dropped.
+const QUEUE_SIZE: usize = 1 << 16;
+
+/// Runs a backend on its own thread behind a bounded queue, so a slow backend
+/// only falls behind itself instead of holding up the audio thread.
+///
+/// Note-ons that don't fit in the queue are dropped and counted, just like an
+/// overloaded synth would drop them. Everything else waits for room, so no note
+/// is left hanging, and lossless backends like `record` never miss anything.
+pub struct QueuedOutput {
+    name: String,
+    inner: Option<Box<dyn OutputBackend>>,
+    lossless: bool,
+    sender: Option<SyncSender<Command>>,
+    worker: Option<thread::JoinHandle<()>>,
+    dropped: u64,
+}
+
+impl QueuedOutput {
+    pub fn new(name: &str, inner: Box<dyn OutputBackend>) -> QueuedOutput {
+        QueuedOutput {
+            name: name.to_string(),
+            lossless: inner.lossless(),
+            inner: Some(inner),
+            sender: None,
+            worker: None,
+            dropped: 0,
+        }
+    }
+
+    /// Waits for room in the queue.
+    fn send(&self, command: Command) {
+        if let Some(sender) = &self.sender {
+            sender.send(command).ok();
+        }
+    }
+
+    /// Drops the note-on if the queue is full, unless the backend is lossless.
+    fn send_note_on(&mut self, message: u32) {
+        if self.lossless {
+            return self.send(Command::Short(message));
+        }
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(Command::Short(message)) {
+                self.dropped += 1;
+            }
+        }
+    }
+}
+
+impl OutputBackend for QueuedOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let mut inner = match self.inner.take() {
+            Some(i) => i,
+            None => return Err("output was already opened".to_string()),
+        };
+        // Opened here so errors reach the caller, then handed over to the worker.
+        inner.open()?;
+
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        self.sender = Some(sender);
+        self.worker = Some(thread::spawn(move || {
+            for command in receiver {
+                match command {
+                    Command::SetTime(time) => inner.set_time(time),
+                    Command::BeforeWait(time, rate) => inner.before_wait(time, rate),
+                    Command::Port(port) => inner.set_port(port),
+                    Command::Short(message) => inner.send_short(message),
+                    Command::SysEx(data) => inner.send_sysex(&data),
+                    Command::Reset => inner.reset(),
+                    Command::Close => break,
+                }
+            }
+            inner.close();
+        }));
+        Ok(())
+    }
+
+    fn set_time(&mut self, time: EventTime) {
+        self.send(Command::SetTime(time));
+    }
+
+    fn before_wait(&mut self, time: EventTime, rate: f64) {
+        self.send(Command::BeforeWait(time, rate));
+    }
+
+    fn set_port(&mut self, port: u8) {
+        self.send(Command::Port(port));
+    }
+
+    fn send_short(&mut self, message: u32) {
+        // Velocity 0 is a note-off, which must get through like everything else.
+        if message & 0xF0 == 0x90 && (message >> 16) & 0x7F != 0 {
+            self.send_note_on(message);
+        } else {
+            self.send(Command::Short(message));
+        }
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        self.send(Command::SysEx(data.to_vec()));
+    }
+
+    fn reset(&mut self) {
+        self.send(Command::Reset);
+    }
+
+    /// Waits for the backend to drain its queue and close.
+    fn close(&mut self) {
+        self.send(Command::Close);
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+        if self.dropped > 0 {
+            say!("\x1b[38;2;255;255;0m{} fell behind, {} note-ons were dropped\x1b[0m", self.name, self.dropped);
+        }
+    }
+}
+
+/// Sends every event to several backends at once, each through its own queue.
+pub struct FanOutput {
+    outputs: Vec<QueuedOutput>,
+}
+
+impl FanOutput {
+    /// Takes each backend with the name it's reported by.
+    pub fn new(outputs: Vec<(String, Box<dyn OutputBackend>)>) -> FanOutput {
+        FanOutput {
+            outputs: outputs.into_iter().map(|(n, o)| QueuedOutput::new(&n, o)).collect(),
+        }
+    }
+}
+
+impl OutputBackend for FanOutput {
+    fn open(&mut self) -> Result<(), String> {
+        for output in self.outputs.iter_mut() {
+            output.open()?;
+        }
+        Ok(())
+    }
+
+    fn set_time(&mut self, time: EventTime) {
+        for output in self.outputs.iter_mut() {
+            output.set_time(time);
+        }
+    }
+
+    fn before_wait(&mut self, time: EventTime, rate: f64) {
+        for output in self.outputs.iter_mut() {
+            output.before_wait(time, rate);
+        }
+    }
+
+    fn set_port(&mut self, port: u8) {
+        for output in self.outputs.iter_mut() {
+            output.set_port(port);
+        }
+    }
+
+    fn send_short(&mut self, message: u32) {
+        for output in self.outputs.iter_mut() {
+            output.send_short(message);
+        }
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        for output in self.outputs.iter_mut() {
+            output.send_sysex(data);
+        }
+    }
+
+    fn reset(&mut self) {
+        for output in self.outputs.iter_mut() {
+            output.reset();
+        }
+    }
+
+    fn close(&mut self) {
+        for output in self.outputs.iter_mut() {
+            output.close();
+        }
+    }
+}
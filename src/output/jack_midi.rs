@@ -0,0 +1,180 @@
+use std::convert::TryInto;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use jack::{AsyncClient, Client, ClientOptions, Control, MidiOut, Port, ProcessHandler, ProcessScope, RawMidi, RingBuffer, RingBufferReader, RingBufferWriter};
+
+use super::{message_length, EventTime, OutputBackend};
+
+/// Bytes of messages that can wait for the process callback, which is also the longest SysEx message sent.
+const QUEUE_SIZE: usize = 1 << 20;
+
+/// Every message in the queue starts with the JACK time (in microseconds) it should play at
+/// and its length, followed by its bytes.
+const HEADER_SIZE: usize = 12;
+
+/// How long sending waits for the process callback to make room, or closing for it to
+/// empty the queue, before giving up on a JACK server that stopped calling it.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct MidiProcess {
+    port: Port<MidiOut>,
+    queue: RingBufferReader,
+    /// Where a message is copied out of the queue, allocated up front so the callback
+    /// never allocates or frees anything.
+    message: Box<[u8]>,
+}
+
+impl ProcessHandler for MidiProcess {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        let n_frames = ps.n_frames();
+        let (cycle_start, cycle_len) = match ps.cycle_times() {
+            Ok(t) => (t.current_usecs, (t.next_usecs.saturating_sub(t.current_usecs)).max(1)),
+            Err(_) => return Control::Continue,
+        };
+
+        let mut writer = self.port.writer(ps);
+        let mut last_frame = 0;
+
+        let mut header = [0u8; HEADER_SIZE];
+        while self.queue.peek(&mut header) == HEADER_SIZE {
+            let time = u64::from_le_bytes(header[..8].try_into().unwrap());
+            let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+            // Belongs to a later cycle, or is still being written. Either way it's read next time.
+            if time >= cycle_start + cycle_len || self.queue.space() < HEADER_SIZE + len {
+                break;
+            }
+            self.queue.advance(HEADER_SIZE);
+            let bytes = &mut self.message[..len];
+            self.queue.read_buffer(bytes);
+
+            let offset = time.saturating_sub(cycle_start) * n_frames as u64 / cycle_len;
+            // JACK wants the events of a buffer in order, so late ones get clamped forward.
+            let frame = (offset as u32).min(n_frames.saturating_sub(1)).max(last_frame);
+            last_frame = frame;
+
+            // A full buffer drops the message, just like an overloaded synth would.
+            writer.write(&RawMidi { time: frame, bytes }).ok();
+        }
+
+        Control::Continue
+    }
+}
+
+/// Waits until `queue` has room for `space` bytes. False if the process callback stopped
+/// being called, e.g. because the JACK server went away.
+fn wait_for_space(queue: &mut RingBufferWriter, space: usize) -> bool {
+    let deadline = Instant::now() + QUEUE_TIMEOUT;
+    while queue.space() < space {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+/// Registers a JACK MIDI output port and stamps every event with a frame offset
+/// taken from its scheduled playback time instead of the time it was sent at.
+pub struct JackOutput {
+    client_name: String,
+    client: Option<AsyncClient<(), MidiProcess>>,
+    queue: Option<RingBufferWriter>,
+    /// How many bytes the queue holds when it's empty.
+    queue_capacity: usize,
+    /// A message being put together before it goes into the queue in one piece.
+    message: Vec<u8>,
+    /// Added to every timestamp so events always land in a cycle that hasn't started yet.
+    latency: u64,
+    target: u64,
+}
+
+impl JackOutput {
+    pub fn new(client_name: &str) -> JackOutput {
+        JackOutput {
+            client_name: client_name.to_string(),
+            client: None,
+            queue: None,
+            queue_capacity: 0,
+            message: Vec::new(),
+            latency: 0,
+            target: 0,
+        }
+    }
+
+    fn queue(&mut self, bytes: &[u8]) {
+        let queue = match &mut self.queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        if HEADER_SIZE + bytes.len() > self.queue_capacity {
+            return;
+        }
+
+        self.message.clear();
+        self.message.extend_from_slice(&self.target.to_le_bytes());
+        self.message.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.message.extend_from_slice(bytes);
+
+        // The queue only fills up when JACK falls behind, so this waits for the next cycles
+        // like a blocking send would.
+        if wait_for_space(queue, self.message.len()) {
+            queue.write_buffer(&self.message);
+        }
+    }
+}
+
+impl OutputBackend for JackOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let (client, _) = Client::new(&self.client_name, ClientOptions::NO_START_SERVER)
+            .map_err(|e| format!("could not connect to the JACK server: {}", e))?;
+        let port = client.register_port("midi_out", MidiOut).map_err(|e| e.to_string())?;
+
+        self.latency = client.buffer_size() as u64 * 1_000_000 / client.sample_rate().max(1) as u64;
+        self.target = jack::get_time() + self.latency;
+
+        let mut ring = RingBuffer::new(QUEUE_SIZE).map_err(|e| e.to_string())?;
+        // Keeps the callback from waiting on the queue being paged in.
+        ring.mlock();
+        let (reader, mut writer) = ring.into_reader_writer();
+        self.queue_capacity = writer.space();
+
+        let process = MidiProcess {
+            port,
+            queue: reader,
+            message: vec![0; self.queue_capacity].into_boxed_slice(),
+        };
+        let active = client.activate_async((), process).map_err(|e| e.to_string())?;
+
+        say!("Opened JACK MIDI port {}:midi_out", self.client_name);
+        self.client = Some(active);
+        self.queue = Some(writer);
+        Ok(())
+    }
+
+    fn before_wait(&mut self, time: EventTime, rate: f64) {
+        // Stamped before the audio thread sleeps, while `scheduled - actual` is still the
+        // time left until the next events are due.
+        let ahead = ((time.scheduled - time.actual) / rate * 1_000_000.0) as i64;
+        self.target = (jack::get_time() as i64 + ahead).max(0) as u64 + self.latency;
+    }
+
+    fn send_short(&mut self, message: u32) {
+        let bytes = message.to_le_bytes();
+        self.queue(&bytes[..message_length(bytes[0])]);
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        self.queue(data);
+    }
+
+    fn close(&mut self) {
+        // Deactivating right away would cut off what's still queued, the last note-offs included.
+        if let Some(mut queue) = self.queue.take() {
+            wait_for_space(&mut queue, self.queue_capacity);
+        }
+        if let Some(client) = self.client.take() {
+            client.deactivate().ok();
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use kdmapi::KDMAPI;
+
+use super::OutputBackend;
+
+/// Sends events straight to OmniMIDI through KDMAPI (Windows only).
+///
+/// The kdmapi crate only wraps `SendDirectData`, so SysEx messages are not sent.
+pub struct KDMAPIOutput {
+    // The stream lives inside the closure and is terminated when it is dropped.
+    send: Option<Box<dyn Fn(u32) + Send>>,
+}
+
+impl KDMAPIOutput {
+    pub fn new() -> KDMAPIOutput {
+        KDMAPIOutput { send: None }
+    }
+}
+
+impl OutputBackend for KDMAPIOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let stream = KDMAPI.open_stream();
+        self.send = Some(Box::new(move |message| {
+            stream.send_direct_data(message);
+        }));
+        Ok(())
+    }
+
+    fn send_short(&mut self, message: u32) {
+        if let Some(send) = &self.send {
+            send(message);
+        }
+    }
+
+    fn send_sysex(&mut self, _data: &[u8]) {
+        // Dropped, see the struct docs.
+    }
+
+    fn close(&mut self) {
+        self.send = None;
+    }
+}
@@ -0,0 +1,197 @@
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+mod alsa_seq;
+mod dll;
+mod fanout;
+#[cfg(feature = "jack")]
+mod jack_midi;
+#[cfg(windows)]
+mod kdmapi;
+mod null;
+mod raw;
+mod record;
+mod router;
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+pub use self::alsa_seq::AlsaOutput;
+pub use self::dll::DllOutput;
+pub use self::fanout::FanOutput;
+#[cfg(feature = "jack")]
+pub use self::jack_midi::JackOutput;
+#[cfg(windows)]
+pub use self::kdmapi::KDMAPIOutput;
+pub use self::null::NullOutput;
+pub use self::raw::RawOutput;
+pub use self::record::RecordOutput;
+pub use self::router::RoutedOutput;
+
+/// Timing of the events that are about to be sent, in seconds of playback.
+#[derive(Clone, Copy, Default)]
+pub struct EventTime {
+    /// When the events are supposed to play (the audio thread's `atime`).
+    pub scheduled: f64,
+    /// The playback position when they were actually sent.
+    pub actual: f64,
+}
+
+/// A sink for the events produced by the audio thread.
+///
+/// Short messages are packed as `status | data1 << 8 | data2 << 16`.
+pub trait OutputBackend: Send {
+    /// Opens the underlying device or stream. Called once before playback starts.
+    fn open(&mut self) -> Result<(), String>;
+
+    /// Whether this backend must get every event, even if waiting for it holds up playback.
+    /// Other backends can miss note-ons when they fall behind, see `QueuedOutput`.
+    fn lossless(&self) -> bool {
+        false
+    }
+
+    /// Called whenever playback moves forward, before the events at that time are sent.
+    fn set_time(&mut self, _time: EventTime) {}
+
+    /// Called once the events before `time.scheduled` are sent and the audio thread is
+    /// about to wait for it. `time.actual` is the position now, moving at `rate`.
+    fn before_wait(&mut self, _time: EventTime, _rate: f64) {}
+
+    /// Called when the following events come from a different MIDI port (FF 21 meta event).
+    fn set_port(&mut self, _port: u8) {}
+
+    /// Sends a single short (channel or system common) message.
+    fn send_short(&mut self, message: u32);
+
+    /// Sends a complete SysEx message, including the leading 0xF0 and trailing 0xF7.
+    fn send_sysex(&mut self, data: &[u8]);
+
+    /// Silences every channel. The default sends All Sound Off and Reset All Controllers.
+    fn reset(&mut self) {
+        silence_channels(self);
+    }
+
+    /// Releases the device. No messages are sent after this.
+    fn close(&mut self);
+}
+
+/// Sends All Sound Off and Reset All Controllers on all 16 channels.
+pub fn silence_channels<O: OutputBackend + ?Sized>(output: &mut O) {
+    for channel in 0..16 {
+        output.send_short(0xB0 | channel | (120 << 8));
+        output.send_short(0xB0 | channel | (121 << 8));
+    }
+}
+
+/// Resets `output` on each of the first `ports` MIDI ports, then switches it to `active`.
+/// `reset` only reaches the port the output is on, notes on the others would keep sounding.
+pub fn reset_ports<O: OutputBackend + ?Sized>(output: &mut O, ports: usize, active: u8) {
+    for port in 0..ports.max(1) {
+        output.set_port(port as u8);
+        output.reset();
+    }
+    output.set_port(active);
+}
+
+/// Number of bytes in a short message with the given status byte.
+pub fn message_length(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 3,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 2,
+        _ => 1,
+    }
+}
+
+/// Names accepted by `-output`.
+pub const BACKEND_NAMES: [&str; 7] = ["kdmapi", "dll", "alsa", "jack", "raw", "null", "record"];
+
+/// What `-output` defaults to: KDMAPI on Windows, elsewhere ALSA or JACK if one of them
+/// was built in. None when there's nothing sensible to play through without asking.
+pub fn default_backend() -> Option<&'static str> {
+    if cfg!(windows) {
+        Some("kdmapi")
+    } else if cfg!(all(target_os = "linux", feature = "alsa")) {
+        Some("alsa")
+    } else if cfg!(feature = "jack") {
+        Some("jack")
+    } else {
+        None
+    }
+}
+
+/// The library `dll` loads without a path: OmniMIDI's, named as it is on this platform
+/// and found wherever the system looks for libraries.
+fn default_dll() -> &'static str {
+    if cfg!(windows) {
+        "OmniMIDI.dll"
+    } else if cfg!(target_os = "macos") {
+        "libOmniMIDI.dylib"
+    } else {
+        "libOmniMIDI.so"
+    }
+}
+
+/// Creates the backend selected with `-output`.
+///
+/// Backends that need a parameter take it after a colon, e.g. `record:capture.mid`.
+/// Several backends separated by commas all receive every event, unless `routes`
+/// (from `-route`) is given. Then outputs can be named with `name=backend` and each
+/// channel goes to the output its route names, see `RoutedOutput`.
+pub fn create_backend(spec: &str, routes: Option<&str>) -> Result<Box<dyn OutputBackend>, String> {
+    if let Some(routes) = routes {
+        let mut outputs = Vec::new();
+        for part in spec.split(',').filter(|p| !p.is_empty()) {
+            let named = match (part.find('='), part.find(':')) {
+                (Some(eq), Some(colon)) => eq < colon,
+                (Some(_), None) => true,
+                _ => false,
+            };
+            let (name, backend) = if named {
+                let eq = part.find('=').unwrap();
+                (&part[..eq], &part[eq + 1..])
+            } else {
+                (part.split(':').next().unwrap_or(part), part)
+            };
+            outputs.push((name.to_string(), create_single_backend(backend)?));
+        }
+        return Ok(Box::new(RoutedOutput::new(outputs, routes)?));
+    }
+
+    if !spec.contains(',') {
+        return create_single_backend(spec);
+    }
+
+    let mut outputs = Vec::new();
+    for part in spec.split(',').filter(|p| !p.is_empty()) {
+        outputs.push((part.to_string(), create_single_backend(part)?));
+    }
+    Ok(Box::new(FanOutput::new(outputs)))
+}
+
+/// Whether any of the backends in `spec` writes to stdout, which `raw` does by default.
+pub fn writes_stdout(spec: &str) -> bool {
+    spec.split(',').any(|part| {
+        let mut parts = part.splitn(2, ':');
+        // Skips a `name=` given for `-route`.
+        let name = parts.next().unwrap_or("").rsplit('=').next().unwrap_or("");
+        name.eq_ignore_ascii_case("raw") && parts.next().unwrap_or("-") == "-"
+    })
+}
+
+fn create_single_backend(spec: &str) -> Result<Box<dyn OutputBackend>, String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or("").to_lowercase();
+    let param = parts.next();
+
+    match name.as_str() {
+        #[cfg(windows)]
+        "kdmapi" => Ok(Box::new(KDMAPIOutput::new())),
+        #[cfg(not(windows))]
+        "kdmapi" => Err("kdmapi is only available on Windows, use dll:<path to libOmniMIDI.so> instead".to_string()),
+        "dll" => Ok(Box::new(DllOutput::new(param.unwrap_or(default_dll())))),
+        #[cfg(all(target_os = "linux", feature = "alsa"))]
+        "alsa" => Ok(Box::new(AlsaOutput::new(param.unwrap_or("UniMIDI")))),
+        #[cfg(feature = "jack")]
+        "jack" => Ok(Box::new(JackOutput::new(param.unwrap_or("UniMIDI")))),
+        "raw" => Ok(Box::new(RawOutput::new(param.unwrap_or("-")))),
+        "null" => Ok(Box::new(NullOutput::new())),
+        "record" => Ok(Box::new(RecordOutput::new(param.unwrap_or("recording.mid")))),
+        _ => Err(format!("unknown output '{}' (available: {})", name, BACKEND_NAMES.join(", "))),
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use super::{EventTime, OutputBackend};
+
+/// Discards every event, but counts them and measures how late they were sent.
+/// Used to benchmark playback on machines without a synth.
+pub struct NullOutput {
+    started: Option<Instant>,
+    lateness: f64,
+    counts: [u64; 256],
+    channel_counts: [u64; 16],
+    sysex_count: u64,
+    total: u64,
+    late_total: f64,
+    late_max: f64,
+    late_over_10ms: u64,
+}
+
+impl NullOutput {
+    pub fn new() -> NullOutput {
+        NullOutput {
+            started: None,
+            lateness: 0.0,
+            counts: [0; 256],
+            channel_counts: [0; 16],
+            sysex_count: 0,
+            total: 0,
+            late_total: 0.0,
+            late_max: 0.0,
+            late_over_10ms: 0,
+        }
+    }
+
+    fn count_lateness(&mut self) {
+        self.total += 1;
+        self.late_total += self.lateness;
+        if self.lateness > self.late_max {
+            self.late_max = self.lateness;
+        }
+        if self.lateness > 0.01 {
+            self.late_over_10ms += 1;
+        }
+    }
+
+    fn print_summary(&self) {
+        let elapsed = match self.started {
+            Some(s) => s.elapsed().as_secs_f64(),
+            None => 0.0,
+        };
+
+        say!("\x1b[38;2;0;255;0mPlayback summary\x1b[0m");
+        say!("Events sent: {} in {:.2}s ({:.0} events/s)", self.total, elapsed, self.total as f64 / elapsed.max(0.000001));
+        if self.total > 0 {
+            say!("Lateness: avg {:.3}ms, max {:.3}ms, {} events over 10ms",
+                self.late_total / self.total as f64 * 1000.0,
+                self.late_max * 1000.0,
+                self.late_over_10ms);
+        }
+
+        say!("Per channel:");
+        for (channel, count) in self.channel_counts.iter().enumerate() {
+            if *count > 0 {
+                say!("  {:>2}: {}", channel, count);
+            }
+        }
+
+        say!("Per status byte:");
+        for (status, count) in self.counts.iter().enumerate() {
+            if *count > 0 {
+                say!("  0x{:02X} ({}): {}", status, status_name(status as u8), count);
+            }
+        }
+        if self.sysex_count > 0 {
+            say!("  0xF0 (SysEx): {}", self.sysex_count);
+        }
+    }
+}
+
+fn status_name(status: u8) -> &'static str {
+    match status & 0xF0 {
+        0x80 => "Note Off",
+        0x90 => "Note On",
+        0xA0 => "Poly Pressure",
+        0xB0 => "Control Change",
+        0xC0 => "Program Change",
+        0xD0 => "Channel Pressure",
+        0xE0 => "Pitch Bend",
+        _ => "System",
+    }
+}
+
+impl OutputBackend for NullOutput {
+    fn open(&mut self) -> Result<(), String> {
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    fn set_time(&mut self, time: EventTime) {
+        self.lateness = (time.actual - time.scheduled).max(0.0);
+    }
+
+    fn send_short(&mut self, message: u32) {
+        let status = (message & 0xFF) as usize;
+        self.counts[status] += 1;
+        if status < 0xF0 {
+            self.channel_counts[status & 0x0F] += 1;
+        }
+        self.count_lateness();
+    }
+
+    fn send_sysex(&mut self, _data: &[u8]) {
+        self.sysex_count += 1;
+        self.count_lateness();
+    }
+
+    fn close(&mut self) {
+        if self.started.is_some() {
+            self.print_summary();
+            self.started = None;
+        }
+    }
+}
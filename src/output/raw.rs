@@ -0,0 +1,77 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+
+use super::{message_length, EventTime, OutputBackend};
+
+/// Writes plain MIDI bytes, without running status, to a file, named pipe or stdout (`-`).
+pub struct RawOutput {
+    path: String,
+    writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    dirty: bool,
+}
+
+impl RawOutput {
+    pub fn new(path: &str) -> RawOutput {
+        RawOutput {
+            path: path.to_string(),
+            writer: None,
+            dirty: false,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(writer) = &mut self.writer {
+            if writer.write_all(bytes).is_err() {
+                // The reader went away (e.g. a closed pipe), stop writing.
+                self.writer = None;
+            }
+            self.dirty = true;
+        }
+    }
+}
+
+impl OutputBackend for RawOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let sink: Box<dyn Write + Send> = if self.path == "-" {
+            Box::new(io::stdout())
+        } else {
+            // Opening a FIFO blocks until something starts reading it.
+            let file = OpenOptions::new().write(true).create(true).truncate(true).open(&self.path)
+                .map_err(|e| format!("{}: {}", self.path, e))?;
+            Box::new(file)
+        };
+        self.writer = Some(BufWriter::new(sink));
+        Ok(())
+    }
+
+    fn lossless(&self) -> bool {
+        true
+    }
+
+    fn before_wait(&mut self, _time: EventTime, _rate: f64) {
+        // Flushed at the end of each group of events, before the audio thread sleeps,
+        // so the reader doesn't get them late.
+        if self.dirty {
+            if let Some(writer) = &mut self.writer {
+                writer.flush().ok();
+            }
+            self.dirty = false;
+        }
+    }
+
+    fn send_short(&mut self, message: u32) {
+        let bytes = message.to_le_bytes();
+        let len = message_length(bytes[0]);
+        self.write(&bytes[..len]);
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        self.write(data);
+    }
+
+    fn close(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush().ok();
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use super::{message_length, EventTime, OutputBackend};
+
+// 10000 ticks per quarter at 1,000,000 us per quarter gives 0.1ms ticks.
+const PPQ: u16 = 10000;
+const TEMPO: u32 = 1_000_000;
+
+/// The largest number a variable length quantity holds, in its 4 bytes of 7 bits.
+const MAX_VAR_LENGTH: u64 = 0x0FFF_FFFF;
+
+/// An empty text event, written to split a delta too long for one variable length quantity.
+const FILLER: [u8; 3] = [0xFF, 0x01, 0x00];
+
+/// The end of track event, which always needs room at the end.
+const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+/// Writes everything the audio thread sends into a type 0 MIDI file,
+/// timestamped with the playback position it was sent at.
+pub struct RecordOutput {
+    path: String,
+    writer: Option<BufWriter<File>>,
+    track_length: u64,
+    /// Why recording stopped early, reported on close.
+    error: Option<String>,
+    time: f64,
+    /// The last playback position, to tell how far playback moved since.
+    position: f64,
+    last_tick: u64,
+}
+
+impl RecordOutput {
+    pub fn new(path: &str) -> RecordOutput {
+        RecordOutput {
+            path: path.to_string(),
+            writer: None,
+            track_length: 0,
+            error: None,
+            time: 0.0,
+            position: 0.0,
+            last_tick: 0,
+        }
+    }
+
+    fn write_event(&mut self, bytes: &[u8]) {
+        if self.writer.is_none() || self.error.is_some() {
+            return;
+        }
+
+        let tick = (self.time * PPQ as f64 * 1_000_000.0 / TEMPO as f64).round().max(0.0) as u64;
+        let mut delta = tick.saturating_sub(self.last_tick);
+
+        let mut buf = Vec::with_capacity(bytes.len() + 5);
+        // Gaps of more than about 7 hours are bridged with filler events.
+        while delta > MAX_VAR_LENGTH {
+            write_var_length(&mut buf, MAX_VAR_LENGTH as u32);
+            buf.extend_from_slice(&FILLER);
+            delta -= MAX_VAR_LENGTH;
+        }
+        write_var_length(&mut buf, delta as u32);
+        buf.extend_from_slice(bytes);
+
+        // The track length is a 32 bit number, so that's where a recording has to end.
+        if self.track_length + (buf.len() + END_OF_TRACK.len()) as u64 > u32::MAX as u64 {
+            self.error = Some("the track reached the 4 GB size limit of MIDI files".to_string());
+            return;
+        }
+
+        if let Some(writer) = &mut self.writer {
+            match writer.write_all(&buf) {
+                Ok(()) => {
+                    self.track_length += buf.len() as u64;
+                    self.last_tick = self.last_tick.max(tick);
+                }
+                Err(err) => self.error = Some(err.to_string()),
+            }
+        }
+    }
+}
+
+/// Appends `value` as a MIDI variable length quantity. Only the low 28 bits fit.
+fn write_var_length(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0u8; 5];
+    let mut len = 0;
+    let mut v = value;
+    loop {
+        bytes[len] = (v & 0x7F) as u8;
+        len += 1;
+        v >>= 7;
+        if v == 0 {
+            break;
+        }
+    }
+    for i in (0..len).rev() {
+        buf.push(if i > 0 { bytes[i] | 0x80 } else { bytes[i] });
+    }
+}
+
+impl OutputBackend for RecordOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let file = File::create(&self.path).map_err(|e| format!("{}: {}", self.path, e))?;
+        let mut writer = BufWriter::new(file);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(b"MThd");
+        header.extend_from_slice(&6u32.to_be_bytes());
+        header.extend_from_slice(&0u16.to_be_bytes());
+        header.extend_from_slice(&1u16.to_be_bytes());
+        header.extend_from_slice(&PPQ.to_be_bytes());
+        header.extend_from_slice(b"MTrk");
+        // Track length, patched in close()
+        header.extend_from_slice(&0u32.to_be_bytes());
+        writer.write_all(&header).map_err(|e| e.to_string())?;
+
+        self.writer = Some(writer);
+        self.track_length = 0;
+        self.last_tick = 0;
+
+        let tempo = TEMPO.to_be_bytes();
+        self.write_event(&[0xFF, 0x51, 0x03, tempo[1], tempo[2], tempo[3]]);
+        Ok(())
+    }
+
+    fn lossless(&self) -> bool {
+        true
+    }
+
+    fn set_time(&mut self, time: EventTime) {
+        // Seeking back carries on from where the recording is instead of rewinding it.
+        if time.actual > self.position {
+            self.time += time.actual - self.position;
+        }
+        self.position = time.actual;
+    }
+
+    fn send_short(&mut self, message: u32) {
+        let status = (message & 0xFF) as u8;
+        // Only channel messages are valid inside a track chunk.
+        if !(0x80..0xF0).contains(&status) {
+            return;
+        }
+        let bytes = message.to_le_bytes();
+        self.write_event(&bytes[..message_length(status)]);
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        // Its length wouldn't fit, and no synth takes a message over 256 MB anyway.
+        if data.is_empty() || data.len() as u64 - 1 > MAX_VAR_LENGTH {
+            return;
+        }
+        let mut bytes = vec![0xF0];
+        write_var_length(&mut bytes, (data.len() - 1) as u32);
+        bytes.extend_from_slice(&data[1..]);
+        self.write_event(&bytes);
+    }
+
+    fn close(&mut self) {
+        let length = self.track_length;
+        self.write_event(&END_OF_TRACK[1..]);
+        if let Some(mut writer) = self.writer.take() {
+            // After an error it goes right after the last event that fit, so the file stays valid.
+            if self.track_length == length && writer.write_all(&END_OF_TRACK).is_ok() {
+                self.track_length += END_OF_TRACK.len() as u64;
+            }
+            if let Ok(mut file) = writer.into_inner() {
+                file.seek(SeekFrom::Start(18)).ok();
+                file.write_all(&(self.track_length as u32).to_be_bytes()).ok();
+            }
+            match &self.error {
+                Some(err) => say!("\x1b[38;2;255;32;32mRecording to {} stopped early: {}\x1b[0m", self.path, err),
+                None => say!("Recorded playback to {}", self.path),
+            }
+        }
+    }
+}
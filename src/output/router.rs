@@ -0,0 +1,131 @@
+use super::fanout::QueuedOutput;
+use super::{EventTime, OutputBackend};
+
+/// Highest number of MIDI ports a route can refer to.
+pub const MAX_PORTS: usize = 16;
+
+/// Sends each channel (per port) to one of several named backends, or drops it.
+///
+/// Routes are written as `[port:]channels=target`, separated by commas and applied
+/// in order, e.g. `9=drums,0-8=synth,3=drop,1:0-15=synth`. Channels and ports count
+/// from 0, `target` is the name of an output or `drop`. Anything not routed goes to
+/// the first output.
+pub struct RoutedOutput {
+    names: Vec<String>,
+    outputs: Vec<QueuedOutput>,
+    routes: Vec<[Option<usize>; 16]>,
+    port: usize,
+}
+
+fn parse_range(text: &str, max: usize) -> Result<(usize, usize), String> {
+    let mut bounds = text.splitn(2, '-');
+    let parse = |s: &str| s.trim().parse::<usize>().map_err(|_| format!("'{}' is not a number", s));
+    let start = parse(bounds.next().unwrap_or(""))?;
+    let end = match bounds.next() {
+        Some(e) => parse(e)?,
+        None => start,
+    };
+    if start > end || end >= max {
+        return Err(format!("'{}' must be between 0 and {}", text, max - 1));
+    }
+    Ok((start, end))
+}
+
+impl RoutedOutput {
+    pub fn new(outputs: Vec<(String, Box<dyn OutputBackend>)>, routes: &str) -> Result<RoutedOutput, String> {
+        let names: Vec<String> = outputs.iter().map(|(n, _)| n.clone()).collect();
+        let mut table = vec![[Some(0); 16]; MAX_PORTS];
+
+        for route in routes.split(',').filter(|r| !r.is_empty()) {
+            let (source, target) = match route.find('=') {
+                Some(i) => (&route[..i], &route[i + 1..]),
+                None => return Err(format!("route '{}' has no '=target'", route)),
+            };
+
+            let destination = if target == "drop" {
+                None
+            } else {
+                match names.iter().position(|n| n == target) {
+                    Some(i) => Some(i),
+                    None => return Err(format!("route '{}' points at unknown output '{}'", route, target)),
+                }
+            };
+
+            let (ports, channels) = match source.find(':') {
+                Some(i) => (parse_range(&source[..i], MAX_PORTS)?, parse_range(&source[i + 1..], 16)?),
+                None => ((0, MAX_PORTS - 1), parse_range(source, 16)?),
+            };
+
+            for port in table.iter_mut().take(ports.1 + 1).skip(ports.0) {
+                for slot in port.iter_mut().take(channels.1 + 1).skip(channels.0) {
+                    *slot = destination;
+                }
+            }
+        }
+
+        Ok(RoutedOutput {
+            names,
+            outputs: outputs.into_iter().map(|(n, o)| QueuedOutput::new(&n, o)).collect(),
+            routes: table,
+            port: 0,
+        })
+    }
+}
+
+impl OutputBackend for RoutedOutput {
+    fn open(&mut self) -> Result<(), String> {
+        for (output, name) in self.outputs.iter_mut().zip(self.names.iter()) {
+            output.open().map_err(|e| format!("{}: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    fn set_time(&mut self, time: EventTime) {
+        for output in self.outputs.iter_mut() {
+            output.set_time(time);
+        }
+    }
+
+    fn before_wait(&mut self, time: EventTime, rate: f64) {
+        for output in self.outputs.iter_mut() {
+            output.before_wait(time, rate);
+        }
+    }
+
+    fn set_port(&mut self, port: u8) {
+        self.port = (port as usize).min(MAX_PORTS - 1);
+        for output in self.outputs.iter_mut() {
+            output.set_port(port);
+        }
+    }
+
+    fn send_short(&mut self, message: u32) {
+        let status = (message & 0xFF) as usize;
+        if status >= 0xF0 {
+            // System messages don't belong to a channel, everyone gets them.
+            for output in self.outputs.iter_mut() {
+                output.send_short(message);
+            }
+        } else if let Some(target) = self.routes[self.port][status & 0x0F] {
+            self.outputs[target].send_short(message);
+        }
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) {
+        for output in self.outputs.iter_mut() {
+            output.send_sysex(data);
+        }
+    }
+
+    fn reset(&mut self) {
+        for output in self.outputs.iter_mut() {
+            output.reset();
+        }
+    }
+
+    fn close(&mut self) {
+        for output in self.outputs.iter_mut() {
+            output.close();
+        }
+    }
+}
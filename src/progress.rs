@@ -0,0 +1,274 @@
+use std::io::{stderr, stdout, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, KeyCode, KeyEvent};
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::QueueableCommand;
+
+const BAR_WIDTH: usize = 30;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Phase {
+    Reading,
+    Decompressing,
+    Counting,
+    Parsing,
+    Merging,
+    Collecting,
+    Indexing,
+}
+
+const PHASES: [Phase; 7] = [Phase::Reading, Phase::Decompressing, Phase::Counting, Phase::Parsing, Phase::Merging, Phase::Collecting, Phase::Indexing];
+const NO_PHASE: usize = usize::MAX;
+
+/// The total of a phase whose size isn't known until it's done, like reading stdin.
+pub const UNKNOWN_TOTAL: u64 = u64::MAX;
+
+fn index(phase: Phase) -> usize {
+    PHASES.iter().position(|p| *p == phase).unwrap()
+}
+
+impl Phase {
+    fn label(self) -> &'static str {
+        match self {
+            Phase::Reading => "Reading file",
+            Phase::Decompressing => "Decompressing",
+            Phase::Counting => "Counting events",
+            Phase::Parsing => "Parsing tracks",
+            Phase::Merging => "Merging tracks",
+            Phase::Collecting => "Timing events",
+            Phase::Indexing => "Indexing seeks",
+        }
+    }
+}
+
+/// Loading progress, updated by the loader and drawn by [`show`].
+///
+/// Every phase keeps its own counts so a finished phase can still be drawn after the next one started.
+pub struct LoadProgress {
+    phase: AtomicUsize,
+    done: [AtomicU64; 7],
+    total: [AtomicU64; 7],
+    tracks_done: AtomicU64,
+    tracks_total: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Default for LoadProgress {
+    fn default() -> LoadProgress {
+        LoadProgress {
+            phase: AtomicUsize::new(NO_PHASE),
+            done: Default::default(),
+            total: Default::default(),
+            tracks_done: AtomicU64::new(0),
+            tracks_total: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+}
+
+impl LoadProgress {
+    pub fn new() -> LoadProgress {
+        LoadProgress::default()
+    }
+
+    /// Moves on to `phase`, which has `total` bytes or events to get through.
+    pub fn start(&self, phase: Phase, total: u64) {
+        self.total[index(phase)].store(total, Ordering::Relaxed);
+        self.phase.store(index(phase), Ordering::Release);
+    }
+
+    /// Updates how far the current phase got.
+    pub fn set(&self, done: u64) {
+        if let Some(done_count) = self.done.get(self.phase.load(Ordering::Acquire)) {
+            done_count.store(done, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds to how far the current phase got, for phases worked on by several threads at once.
+    pub fn add(&self, done: u64) {
+        if let Some(done_count) = self.done.get(self.phase.load(Ordering::Acquire)) {
+            done_count.fetch_add(done, Ordering::Relaxed);
+        }
+    }
+
+    /// Makes what the current phase got through its total, once a phase with an unknown total is done.
+    pub fn finish_phase(&self) {
+        let phase = self.phase.load(Ordering::Acquire);
+        if let (Some(done), Some(total)) = (self.done.get(phase), self.total.get(phase)) {
+            total.store(done.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_tracks(&self, done: u64, total: u64) {
+        self.tracks_done.store(done, Ordering::Relaxed);
+        self.tracks_total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn track_done(&self) {
+        self.tracks_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn phase(&self) -> Option<Phase> {
+        PHASES.get(self.phase.load(Ordering::Acquire)).copied()
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    if bytes >= 1 << 30 {
+        format!("{:.2} GB", bytes as f64 / (1u64 << 30) as f64)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1u64 << 20) as f64)
+    }
+}
+
+fn format_count(count: u64) -> String {
+    if count >= 1_000_000 {
+        format!("{:.1}M", count as f64 / 1_000_000.0)
+    } else if count >= 1000 {
+        format!("{:.1}K", count as f64 / 1000.0)
+    } else {
+        count.to_string()
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Draws one phase as `label [=====>    ]  52.3%  details  ETA 0:12`.
+/// The last draw of a phase shows how long it took and ends the line.
+fn draw_line(out: &mut impl Write, progress: &LoadProgress, phase: Phase, started: Instant, last: bool) {
+    let total = progress.total[index(phase)].load(Ordering::Relaxed);
+    let done = progress.done[index(phase)].load(Ordering::Relaxed).min(total);
+    let unknown = total == UNKNOWN_TOTAL;
+    let fraction = if unknown { 0.0 } else if total == 0 { 1.0 } else { done as f64 / total as f64 };
+
+    let filled = (fraction * BAR_WIDTH as f64) as usize;
+    let mut bar = "=".repeat(filled);
+    if filled < BAR_WIDTH {
+        bar.push('>');
+        bar.push_str(&" ".repeat(BAR_WIDTH - filled - 1));
+    }
+
+    let details = match phase {
+        Phase::Reading | Phase::Decompressing if unknown => format_bytes(done),
+        Phase::Reading | Phase::Decompressing | Phase::Counting => format!("{} / {}", format_bytes(done), format_bytes(total)),
+        Phase::Parsing => {
+            let tracks_total = progress.tracks_total.load(Ordering::Relaxed);
+            let tracks_done = progress.tracks_done.load(Ordering::Relaxed);
+            format!("{} / {}, {} / {} tracks", format_bytes(done), format_bytes(total), tracks_done, tracks_total)
+        }
+        // Every level of the merge tree copies each event once.
+        Phase::Merging => format!("{} / {} events copied", format_count(done), format_count(total)),
+        Phase::Collecting | Phase::Indexing => format!("{} / {} events", format_count(done), format_count(total)),
+    };
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let timing = if last {
+        format!("took {}", format_duration(elapsed))
+    } else if fraction > 0.0 {
+        format!("ETA {}", format_duration(elapsed / fraction - elapsed))
+    } else {
+        "ETA --:--".to_string()
+    };
+
+    out.write_all(b"\r").ok();
+    out.queue(Clear(ClearType::CurrentLine)).ok();
+    write!(out, "\x1b[38;2;0;255;0m{:<16}\x1b[0m[{}] {:5.1}%  {}  {}", phase.label(), bar, fraction * 100.0, details, timing).ok();
+    if last {
+        out.write_all(b"\r\n").ok();
+    }
+    out.flush().ok();
+}
+
+/// Keeps drawing the loading progress until it's dropped or [`ProgressDisplay::finish`] is called.
+pub struct ProgressDisplay {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Draws each phase on its own line, redrawing the current one in place. Esc cancels loading.
+pub fn show(progress: Arc<LoadProgress>) -> ProgressDisplay {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_thread = Arc::clone(&stop);
+
+    let thread = thread::spawn(move || {
+        let mut out: Box<dyn Write> = if crate::console::headless() {
+            Box::new(stderr())
+        } else {
+            Box::new(stdout())
+        };
+        let mut current: Option<(Phase, Instant)> = None;
+
+        loop {
+            let stopping = stop_thread.load(Ordering::Relaxed);
+            let phase = progress.phase();
+
+            if let Some((shown, started)) = current {
+                if Some(shown) != phase || stopping {
+                    // The loader moved on, or stopped.
+                    draw_line(&mut out, &progress, shown, started, true);
+                    current = None;
+                }
+            }
+            if stopping {
+                break;
+            }
+
+            if let Some(phase) = phase {
+                let (_, started) = *current.get_or_insert((phase, Instant::now()));
+                draw_line(&mut out, &progress, phase, started, false);
+            }
+
+            // Doubles as the redraw interval.
+            match event::poll(Duration::from_millis(100)) {
+                Ok(true) => {
+                    if let Ok(event::Event::Key(KeyEvent { code: KeyCode::Esc, .. })) = event::read() {
+                        progress.cancel();
+                    }
+                }
+                Ok(false) => {}
+                // No terminal to read keys from.
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    });
+
+    ProgressDisplay {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+impl ProgressDisplay {
+    /// Draws the last phase one more time and stops, so nothing else reads keys meant for the player.
+    pub fn finish(mut self) {
+        self.stop_thread();
+    }
+
+    fn stop_thread(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for ProgressDisplay {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
@@ -0,0 +1,105 @@
+mod sf2;
+mod synth;
+mod wav;
+
+pub use self::sf2::SoundFont;
+pub use self::synth::Synth;
+pub use self::wav::WavWriter;
+
+use crate::output::{EventTime, OutputBackend};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Frames rendered per call into the synth.
+const BLOCK_SIZE: usize = 256;
+
+// How long to keep rendering after the last event so releases can ring out.
+const TAIL_SECONDS: f64 = 10.0;
+
+/// Renders events through the built-in SoundFont synth into a WAV file.
+///
+/// Time only moves forward through `set_time`, so the driver decides how fast
+/// rendering runs; the render mode in `main` feeds it as fast as it can.
+pub struct RenderOutput {
+    path: String,
+    synth: Option<Synth>,
+    wav: Option<WavWriter>,
+    frames_rendered: u64,
+    buffer: Vec<f32>,
+}
+
+impl RenderOutput {
+    pub fn new(path: &str, soundfont: SoundFont, max_voices: usize) -> RenderOutput {
+        RenderOutput {
+            path: path.to_string(),
+            synth: Some(Synth::new(soundfont, SAMPLE_RATE, max_voices)),
+            wav: None,
+            frames_rendered: 0,
+            buffer: vec![0.0; BLOCK_SIZE * 2],
+        }
+    }
+
+    /// Seconds of audio written so far.
+    pub fn rendered_seconds(&self) -> f64 {
+        self.frames_rendered as f64 / SAMPLE_RATE as f64
+    }
+
+    fn render_until(&mut self, seconds: f64) {
+        let target = (seconds.max(0.0) * SAMPLE_RATE as f64) as u64;
+        let (synth, wav) = match (&mut self.synth, &mut self.wav) {
+            (Some(s), Some(w)) => (s, w),
+            _ => return,
+        };
+
+        while self.frames_rendered < target {
+            let frames = ((target - self.frames_rendered) as usize).min(BLOCK_SIZE);
+            let block = &mut self.buffer[..frames * 2];
+            synth.render(block);
+            wav.write_samples(block).ok();
+            self.frames_rendered += frames as u64;
+        }
+    }
+}
+
+impl OutputBackend for RenderOutput {
+    fn open(&mut self) -> Result<(), String> {
+        let wav = WavWriter::create(&self.path, SAMPLE_RATE).map_err(|e| format!("{}: {}", self.path, e))?;
+        self.wav = Some(wav);
+        self.frames_rendered = 0;
+        Ok(())
+    }
+
+    fn set_time(&mut self, time: EventTime) {
+        self.render_until(time.scheduled);
+    }
+
+    fn send_short(&mut self, message: u32) {
+        if let Some(synth) = &mut self.synth {
+            synth.send_short(message);
+        }
+    }
+
+    fn send_sysex(&mut self, _data: &[u8]) {}
+
+    fn reset(&mut self) {
+        if let Some(synth) = &mut self.synth {
+            synth.reset();
+        }
+    }
+
+    fn close(&mut self) {
+        // Let the remaining voices finish, up to a limit for notes that never end.
+        let end = self.rendered_seconds() + TAIL_SECONDS;
+        while self.rendered_seconds() < end && self.synth.as_ref().map_or(0, |s| s.active_voices()) > 0 {
+            let next = self.rendered_seconds() + 0.1;
+            self.render_until(next);
+        }
+
+        if let Some(wav) = self.wav.take() {
+            if let Err(e) = wav.finish() {
+                println!("\x1b[38;2;255;32;32mCould not finish {}: {}\x1b[0m", self.path, e);
+            }
+        }
+        self.synth = None;
+    }
+}
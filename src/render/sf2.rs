@@ -0,0 +1,369 @@
+use std::fs;
+
+// Generator operators used by the synth (SoundFont 2.04, section 8.1.2)
+const GEN_START_OFFSET: usize = 0;
+const GEN_END_OFFSET: usize = 1;
+const GEN_START_LOOP_OFFSET: usize = 2;
+const GEN_END_LOOP_OFFSET: usize = 3;
+const GEN_START_COARSE_OFFSET: usize = 4;
+const GEN_END_COARSE_OFFSET: usize = 12;
+const GEN_PAN: usize = 17;
+const GEN_DELAY_VOL_ENV: usize = 33;
+const GEN_ATTACK_VOL_ENV: usize = 34;
+const GEN_HOLD_VOL_ENV: usize = 35;
+const GEN_DECAY_VOL_ENV: usize = 36;
+const GEN_SUSTAIN_VOL_ENV: usize = 37;
+const GEN_RELEASE_VOL_ENV: usize = 38;
+const GEN_INSTRUMENT: usize = 41;
+const GEN_KEY_RANGE: usize = 43;
+const GEN_VEL_RANGE: usize = 44;
+const GEN_START_LOOP_COARSE_OFFSET: usize = 45;
+const GEN_INITIAL_ATTENUATION: usize = 48;
+const GEN_END_LOOP_COARSE_OFFSET: usize = 50;
+const GEN_COARSE_TUNE: usize = 51;
+const GEN_FINE_TUNE: usize = 52;
+const GEN_SAMPLE_ID: usize = 53;
+const GEN_SAMPLE_MODES: usize = 54;
+const GEN_SCALE_TUNING: usize = 56;
+const GEN_EXCLUSIVE_CLASS: usize = 57;
+const GEN_OVERRIDING_ROOT_KEY: usize = 58;
+const GEN_COUNT: usize = 61;
+
+/// A playable key/velocity region of a preset, with every generator already resolved.
+#[derive(Clone)]
+pub struct Region {
+    pub key_lo: u8,
+    pub key_hi: u8,
+    pub vel_lo: u8,
+    pub vel_hi: u8,
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub looping: bool,
+    pub sample_rate: f64,
+    pub root_key: i32,
+    /// Tuning in cents, including the sample's pitch correction.
+    pub tune: i32,
+    pub scale_tuning: i32,
+    /// Linear gain from the initial attenuation.
+    pub gain: f32,
+    /// -500 (left) to 500 (right).
+    pub pan: i32,
+    pub delay: f64,
+    pub attack: f64,
+    pub hold: f64,
+    pub decay: f64,
+    /// Linear sustain level, 0.0 to 1.0.
+    pub sustain: f32,
+    pub release: f64,
+    pub exclusive_class: i32,
+}
+
+pub struct Preset {
+    pub bank: u16,
+    pub program: u16,
+    pub regions: Vec<Region>,
+}
+
+/// The parts of a SoundFont 2 bank needed for sample playback.
+pub struct SoundFont {
+    pub samples: Vec<i16>,
+    pub presets: Vec<Preset>,
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+}
+
+#[derive(Clone, Copy)]
+struct Generators {
+    values: [i16; GEN_COUNT],
+    set: u64,
+}
+
+impl Generators {
+    fn new() -> Generators {
+        Generators { values: [0; GEN_COUNT], set: 0 }
+    }
+
+    fn is_set(&self, op: usize) -> bool {
+        self.set & (1 << op) != 0
+    }
+
+    fn get(&self, op: usize) -> Option<i16> {
+        if self.is_set(op) {
+            Some(self.values[op])
+        } else {
+            None
+        }
+    }
+
+    fn put(&mut self, op: usize, value: i16) {
+        if op < GEN_COUNT {
+            self.values[op] = value;
+            self.set |= 1 << op;
+        }
+    }
+
+    fn range(&self, op: usize) -> (u8, u8) {
+        match self.get(op) {
+            Some(v) => ((v as u16 & 0xFF) as u8, (v as u16 >> 8) as u8),
+            None => (0, 127),
+        }
+    }
+
+    /// Overlays `local` on top of this (global) zone.
+    fn merged(&self, local: &Generators) -> Generators {
+        let mut out = *self;
+        for op in 0..GEN_COUNT {
+            if local.is_set(op) {
+                out.put(op, local.values[op]);
+            }
+        }
+        out
+    }
+}
+
+struct Zone {
+    gens: Generators,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/// Iterates over the sub-chunks of a RIFF list body, yielding (id, body).
+fn chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = read_u32(data, pos + 4) as usize;
+        let body_end = (pos + 8 + len).min(data.len());
+        out.push((id, &data[pos + 8..body_end]));
+        pos += 8 + len + (len & 1);
+    }
+    out
+}
+
+fn timecents_to_secs(tc: i32) -> f64 {
+    if tc <= -12000 {
+        0.0
+    } else {
+        2f64.powf(tc as f64 / 1200.0)
+    }
+}
+
+fn centibels_to_gain(cb: i32) -> f32 {
+    10f32.powf(-(cb.max(0) as f32) / 200.0)
+}
+
+/// Reads the zones of every preset or instrument in a `phdr`/`inst` style table.
+/// `header_size` is the record size and `bag_offset` the position of the bag index in it.
+fn read_zones(headers: &[u8], header_size: usize, bag_offset: usize, bags: &[u8], gens: &[u8]) -> Vec<Vec<Zone>> {
+    let count = headers.len() / header_size;
+    let mut out = Vec::new();
+
+    // The last record is a terminator, it only marks where the previous one ends.
+    for i in 0..count.saturating_sub(1) {
+        let bag_start = read_u16(headers, i * header_size + bag_offset) as usize;
+        let bag_end = read_u16(headers, (i + 1) * header_size + bag_offset) as usize;
+        let mut zones = Vec::new();
+
+        for bag in bag_start..bag_end {
+            if (bag + 1) * 4 + 2 > bags.len() {
+                break;
+            }
+            let gen_start = read_u16(bags, bag * 4) as usize;
+            let gen_end = read_u16(bags, (bag + 1) * 4) as usize;
+            let mut zone = Zone { gens: Generators::new() };
+            for g in gen_start..gen_end {
+                if (g + 1) * 4 > gens.len() {
+                    break;
+                }
+                let op = read_u16(gens, g * 4) as usize;
+                let amount = read_u16(gens, g * 4 + 2) as i16;
+                zone.gens.put(op, amount);
+            }
+            zones.push(zone);
+        }
+        out.push(zones);
+    }
+    out
+}
+
+/// Splits zones into the optional global zone and the local ones.
+/// A global zone is a first zone that doesn't end with the terminal generator.
+fn split_global(zones: &[Zone], terminal: usize) -> (Generators, &[Zone]) {
+    match zones.first() {
+        Some(first) if !first.gens.is_set(terminal) => (first.gens, &zones[1..]),
+        _ => (Generators::new(), zones),
+    }
+}
+
+impl SoundFont {
+    pub fn open(path: &str) -> Result<SoundFont, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+            return Err(format!("{} is not a SoundFont 2 file", path));
+        }
+
+        let mut smpl: &[u8] = &[];
+        let mut pdta = Vec::new();
+        for (id, body) in chunks(&data[12..]) {
+            if id == b"LIST" && body.len() >= 4 {
+                match &body[0..4] {
+                    b"sdta" => {
+                        for (sub_id, sub) in chunks(&body[4..]) {
+                            if sub_id == b"smpl" {
+                                smpl = sub;
+                            }
+                        }
+                    }
+                    b"pdta" => pdta = chunks(&body[4..]),
+                    _ => {}
+                }
+            }
+        }
+
+        let find = |name: &[u8]| -> &[u8] {
+            pdta.iter().find(|(id, _)| *id == name).map(|(_, b)| *b).unwrap_or(&[])
+        };
+
+        let samples: Vec<i16> = smpl.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+
+        let shdr = find(b"shdr");
+        let sample_headers: Vec<SampleHeader> = (0..shdr.len() / 46)
+            .map(|i| {
+                let p = i * 46;
+                SampleHeader {
+                    start: read_u32(shdr, p + 20),
+                    end: read_u32(shdr, p + 24),
+                    loop_start: read_u32(shdr, p + 28),
+                    loop_end: read_u32(shdr, p + 32),
+                    sample_rate: read_u32(shdr, p + 36),
+                    original_pitch: shdr[p + 40],
+                    pitch_correction: shdr[p + 41] as i8,
+                }
+            })
+            .collect();
+
+        let instruments = read_zones(find(b"inst"), 22, 20, find(b"ibag"), find(b"igen"));
+        let preset_zones = read_zones(find(b"phdr"), 38, 24, find(b"pbag"), find(b"pgen"));
+        let phdr = find(b"phdr");
+
+        let mut presets = Vec::new();
+        for (i, zones) in preset_zones.iter().enumerate() {
+            let program = read_u16(phdr, i * 38 + 20);
+            let bank = read_u16(phdr, i * 38 + 22);
+            let (preset_global, preset_local) = split_global(zones, GEN_INSTRUMENT);
+
+            let mut regions = Vec::new();
+            for pzone in preset_local {
+                let pgens = preset_global.merged(&pzone.gens);
+                let inst = match pgens.get(GEN_INSTRUMENT).and_then(|i| instruments.get(i as u16 as usize)) {
+                    Some(inst) => inst,
+                    None => continue,
+                };
+                let (inst_global, inst_local) = split_global(inst, GEN_SAMPLE_ID);
+
+                for izone in inst_local {
+                    let igens = inst_global.merged(&izone.gens);
+                    let header = match igens.get(GEN_SAMPLE_ID).and_then(|s| sample_headers.get(s as u16 as usize)) {
+                        Some(h) => h,
+                        None => continue,
+                    };
+                    if let Some(region) = resolve_region(&pgens, &igens, header, samples.len()) {
+                        regions.push(region);
+                    }
+                }
+            }
+
+            presets.push(Preset { bank, program, regions });
+        }
+
+        Ok(SoundFont { samples, presets })
+    }
+
+    /// Finds the preset for a bank and program. Melodic banks fall back to bank 0 and then
+    /// to the first preset, the drum bank (128) falls back to the standard kit.
+    /// Returns its index in `presets`.
+    pub fn find_preset(&self, bank: u16, program: u16) -> Option<usize> {
+        let find = |bank: u16, program: u16| self.presets.iter().position(|p| p.bank == bank && p.program == program);
+        let exact = find(bank, program);
+        if bank == 128 {
+            exact.or_else(|| find(128, 0))
+        } else if self.presets.is_empty() {
+            None
+        } else {
+            exact.or_else(|| find(0, program)).or(Some(0))
+        }
+    }
+}
+
+fn resolve_region(pgens: &Generators, igens: &Generators, header: &SampleHeader, sample_count: usize) -> Option<Region> {
+    // Preset generators are relative and add to the instrument ones.
+    let sum = |op: usize, default: i32| -> i32 {
+        igens.get(op).map(|v| v as i32).unwrap_or(default) + pgens.get(op).map(|v| v as i32).unwrap_or(0)
+    };
+    let inst = |op: usize, default: i32| -> i32 { igens.get(op).map(|v| v as i32).unwrap_or(default) };
+
+    let (ikey_lo, ikey_hi) = igens.range(GEN_KEY_RANGE);
+    let (pkey_lo, pkey_hi) = pgens.range(GEN_KEY_RANGE);
+    let (ivel_lo, ivel_hi) = igens.range(GEN_VEL_RANGE);
+    let (pvel_lo, pvel_hi) = pgens.range(GEN_VEL_RANGE);
+
+    let offset = |fine: usize, coarse: usize| -> i64 { inst(fine, 0) as i64 + inst(coarse, 0) as i64 * 32768 };
+
+    let clamp = |v: i64| -> usize { v.max(0).min(sample_count as i64) as usize };
+    let start = clamp(header.start as i64 + offset(GEN_START_OFFSET, GEN_START_COARSE_OFFSET));
+    let end = clamp(header.end as i64 + offset(GEN_END_OFFSET, GEN_END_COARSE_OFFSET));
+    let loop_start = clamp(header.loop_start as i64 + offset(GEN_START_LOOP_OFFSET, GEN_START_LOOP_COARSE_OFFSET));
+    let loop_end = clamp(header.loop_end as i64 + offset(GEN_END_LOOP_OFFSET, GEN_END_LOOP_COARSE_OFFSET));
+
+    if end <= start {
+        return None;
+    }
+
+    let root_key = match inst(GEN_OVERRIDING_ROOT_KEY, -1) {
+        -1 => header.original_pitch as i32,
+        k => k,
+    };
+    let sample_modes = inst(GEN_SAMPLE_MODES, 0);
+
+    Some(Region {
+        key_lo: ikey_lo.max(pkey_lo),
+        key_hi: ikey_hi.min(pkey_hi),
+        vel_lo: ivel_lo.max(pvel_lo),
+        vel_hi: ivel_hi.min(pvel_hi),
+        start,
+        end,
+        loop_start,
+        loop_end,
+        looping: (sample_modes == 1 || sample_modes == 3) && loop_end > loop_start && loop_end <= end,
+        sample_rate: header.sample_rate.max(1) as f64,
+        root_key,
+        tune: sum(GEN_COARSE_TUNE, 0) * 100 + sum(GEN_FINE_TUNE, 0) + header.pitch_correction as i32,
+        scale_tuning: sum(GEN_SCALE_TUNING, 100),
+        gain: centibels_to_gain(sum(GEN_INITIAL_ATTENUATION, 0)),
+        pan: sum(GEN_PAN, 0).clamp(-500, 500),
+        delay: timecents_to_secs(sum(GEN_DELAY_VOL_ENV, -12000)),
+        attack: timecents_to_secs(sum(GEN_ATTACK_VOL_ENV, -12000)),
+        hold: timecents_to_secs(sum(GEN_HOLD_VOL_ENV, -12000)),
+        decay: timecents_to_secs(sum(GEN_DECAY_VOL_ENV, -12000)),
+        sustain: centibels_to_gain(sum(GEN_SUSTAIN_VOL_ENV, 0).min(1440)),
+        release: timecents_to_secs(sum(GEN_RELEASE_VOL_ENV, -12000)),
+        exclusive_class: inst(GEN_EXCLUSIVE_CLASS, 0),
+    })
+}
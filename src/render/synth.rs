@@ -0,0 +1,389 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use super::sf2::{Region, SoundFont};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    Finished,
+}
+
+// Envelope levels below this are treated as silent (-80dB).
+const SILENCE: f32 = 0.0001;
+
+struct Voice {
+    channel: u8,
+    key: u8,
+    /// Where its region is in the SoundFont, see [`Voice::region`].
+    preset: usize,
+    region: usize,
+    pos: f64,
+    /// Playback rate without pitch bend, in source samples per output sample.
+    step: f64,
+    velocity_gain: f32,
+    stage: Stage,
+    stage_time: f64,
+    level: f32,
+    release_factor: f32,
+    held_by_pedal: bool,
+    age: u64,
+}
+
+impl Voice {
+    fn region<'a>(&self, soundfont: &'a SoundFont) -> &'a Region {
+        &soundfont.presets[self.preset].regions[self.region]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    bank: u16,
+    program: u16,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    sustain: bool,
+    pitch_bend: i32,
+    bend_range: f64,
+    rpn: (u8, u8),
+}
+
+impl Channel {
+    fn new(index: usize) -> Channel {
+        Channel {
+            bank: if index == 9 { 128 } else { 0 },
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            sustain: false,
+            pitch_bend: 0,
+            bend_range: 2.0,
+            rpn: (127, 127),
+        }
+    }
+}
+
+/// Voices to steal once the limit is reached: releasing ones from the quietest, then
+/// the rest from the oldest. Entries are `(still playing, level bits or age, index, age)`.
+///
+/// Levels only change while rendering and voices only move when finished ones are
+/// removed after a block, so this is rebuilt at most once per block, and only if a
+/// voice has to be stolen. Stolen voices are replaced in place to keep indices valid.
+struct Victims {
+    heap: BinaryHeap<Reverse<(bool, u64, usize, u64)>>,
+    stale: bool,
+}
+
+impl Victims {
+    fn rebuild(&mut self, voices: &[Voice]) {
+        let entries: Vec<_> = voices.iter().enumerate().map(|(i, v)| {
+            let playing = v.stage != Stage::Release && v.stage != Stage::Finished;
+            // Levels are never negative, so their bits sort like the levels.
+            let order = if playing { v.age } else { v.level.to_bits() as u64 };
+            Reverse((playing, order, i, v.age))
+        }).collect();
+        self.heap = BinaryHeap::from(entries);
+        self.stale = false;
+    }
+
+    /// The index of the voice to replace.
+    fn pop(&mut self, voices: &[Voice]) -> usize {
+        loop {
+            if self.stale || self.heap.is_empty() {
+                self.rebuild(voices);
+            }
+            let Reverse((_, _, index, age)) = self.heap.pop().unwrap();
+            // Skips voices that were already replaced since the rebuild.
+            if voices[index].age == age {
+                return index;
+            }
+        }
+    }
+}
+
+/// A SoundFont sample-playback synth with a hard voice limit.
+pub struct Synth {
+    soundfont: SoundFont,
+    sample_rate: f64,
+    max_voices: usize,
+    voices: Vec<Voice>,
+    victims: Victims,
+    channels: [Channel; 16],
+    note_count: u64,
+}
+
+impl Synth {
+    pub fn new(soundfont: SoundFont, sample_rate: u32, max_voices: usize) -> Synth {
+        let mut channels = [Channel::new(0); 16];
+        for (i, c) in channels.iter_mut().enumerate() {
+            *c = Channel::new(i);
+        }
+        Synth {
+            soundfont,
+            sample_rate: sample_rate as f64,
+            max_voices: max_voices.max(1),
+            voices: Vec::with_capacity(max_voices),
+            victims: Victims { heap: BinaryHeap::new(), stale: true },
+            channels,
+            note_count: 0,
+        }
+    }
+
+    pub fn active_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn send_short(&mut self, message: u32) {
+        let status = (message & 0xF0) as u8;
+        let channel = (message & 0x0F) as u8;
+        let data1 = ((message >> 8) & 0x7F) as u8;
+        let data2 = ((message >> 16) & 0x7F) as u8;
+
+        match status {
+            0x90 if data2 > 0 => self.note_on(channel, data1, data2),
+            0x80 | 0x90 => self.note_off(channel, data1),
+            0xB0 => self.control_change(channel, data1, data2),
+            0xC0 => self.channels[channel as usize].program = data1 as u16,
+            0xE0 => self.channels[channel as usize].pitch_bend = ((data2 as i32) << 7 | data1 as i32) - 8192,
+            _ => {}
+        }
+    }
+
+    /// Stops every voice immediately and resets all controllers.
+    pub fn reset(&mut self) {
+        self.voices.clear();
+        self.victims.stale = true;
+        for (i, c) in self.channels.iter_mut().enumerate() {
+            *c = Channel::new(i);
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let ch = self.channels[channel as usize];
+        let preset = match self.soundfont.find_preset(ch.bank, ch.program) {
+            Some(p) => p,
+            None => return,
+        };
+
+        // Only the SoundFont is borrowed, so voices can start while going through it.
+        let soundfont = &self.soundfont;
+        for (region_index, region) in soundfont.presets[preset].regions.iter().enumerate() {
+            if key < region.key_lo || key > region.key_hi || velocity < region.vel_lo || velocity > region.vel_hi {
+                continue;
+            }
+
+            if region.exclusive_class != 0 {
+                for v in self.voices.iter_mut() {
+                    if v.channel == channel && v.region(soundfont).exclusive_class == region.exclusive_class {
+                        v.stage = Stage::Finished;
+                    }
+                }
+            }
+
+            let semitones = (key as f64 - region.root_key as f64) * region.scale_tuning as f64 / 100.0 + region.tune as f64 / 100.0;
+            let step = 2f64.powf(semitones / 12.0) * region.sample_rate / self.sample_rate;
+            let vel = velocity as f32 / 127.0;
+
+            self.note_count += 1;
+            let voice = Voice {
+                channel,
+                key,
+                pos: region.start as f64,
+                step,
+                velocity_gain: vel * vel * region.gain,
+                stage: Stage::Delay,
+                stage_time: 0.0,
+                level: 0.0,
+                release_factor: 0.0,
+                held_by_pedal: false,
+                age: self.note_count,
+                preset,
+                region: region_index,
+            };
+
+            if self.voices.len() < self.max_voices {
+                self.voices.push(voice);
+            } else {
+                let victim = self.victims.pop(&self.voices);
+                self.voices[victim] = voice;
+            }
+        }
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        let sustain = self.channels[channel as usize].sustain;
+        for v in self.voices.iter_mut() {
+            if v.channel == channel && v.key == key && v.stage != Stage::Release && !v.held_by_pedal {
+                if sustain {
+                    v.held_by_pedal = true;
+                } else {
+                    release(v, &self.soundfont, self.sample_rate);
+                }
+            }
+        }
+    }
+
+    fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+        let sample_rate = self.sample_rate;
+        let ch = &mut self.channels[channel as usize];
+        match controller {
+            // Channel 10 always stays on the drum bank.
+            0 if channel != 9 => ch.bank = value as u16,
+            6 if ch.rpn == (0, 0) => ch.bend_range = value as f64,
+            7 => ch.volume = value,
+            10 => ch.pan = value,
+            11 => ch.expression = value,
+            64 => {
+                ch.sustain = value >= 64;
+                if !ch.sustain {
+                    for v in self.voices.iter_mut() {
+                        if v.channel == channel && v.held_by_pedal {
+                            v.held_by_pedal = false;
+                            release(v, &self.soundfont, sample_rate);
+                        }
+                    }
+                }
+            }
+            100 => ch.rpn.1 = value,
+            101 => ch.rpn.0 = value,
+            120 => {
+                self.voices.retain(|v| v.channel != channel);
+                self.victims.stale = true;
+            }
+            121 => {
+                let bank = ch.bank;
+                let program = ch.program;
+                *ch = Channel::new(channel as usize);
+                ch.bank = bank;
+                ch.program = program;
+            }
+            123 => {
+                for v in self.voices.iter_mut() {
+                    if v.channel == channel && v.stage != Stage::Release {
+                        release(v, &self.soundfont, sample_rate);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Mixes every voice into `out`, which holds interleaved stereo frames.
+    pub fn render(&mut self, out: &mut [f32]) {
+        for s in out.iter_mut() {
+            *s = 0.0;
+        }
+
+        let soundfont = &self.soundfont;
+        let samples = &soundfont.samples;
+        let channels = &self.channels;
+        let dt = 1.0 / self.sample_rate;
+
+        for v in self.voices.iter_mut() {
+            let ch = &channels[v.channel as usize];
+            let region = v.region(soundfont);
+            let bend = 2f64.powf(ch.pitch_bend as f64 / 8192.0 * ch.bend_range / 12.0);
+            let step = v.step * bend;
+
+            let volume = ch.volume as f32 / 127.0;
+            let expression = ch.expression as f32 / 127.0;
+            let gain = v.velocity_gain * volume * volume * expression * expression;
+
+            let pan = ((region.pan as f32 / 500.0) + (ch.pan as f32 - 64.0) / 64.0).clamp(-1.0, 1.0);
+            let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            let (left, right) = (angle.cos() * gain, angle.sin() * gain);
+
+            for frame in out.chunks_exact_mut(2) {
+                if !advance_envelope(v, region, dt) {
+                    break;
+                }
+
+                let idx = v.pos as usize;
+                if idx + 1 >= region.end.min(samples.len()) {
+                    v.stage = Stage::Finished;
+                    break;
+                }
+                let frac = (v.pos - idx as f64) as f32;
+                let s = samples[idx] as f32 * (1.0 - frac) + samples[idx + 1] as f32 * frac;
+                let s = s / 32768.0 * v.level;
+
+                frame[0] += s * left;
+                frame[1] += s * right;
+
+                v.pos += step;
+                if region.looping && v.pos >= region.loop_end as f64 {
+                    v.pos -= (region.loop_end - region.loop_start) as f64;
+                }
+            }
+        }
+
+        self.voices.retain(|v| v.stage != Stage::Finished);
+        self.victims.stale = true;
+    }
+}
+
+fn release(v: &mut Voice, soundfont: &SoundFont, sample_rate: f64) {
+    v.stage = Stage::Release;
+    v.stage_time = 0.0;
+    // The SoundFont release time is the time it takes to fall by 100dB.
+    let samples = (v.region(soundfont).release * sample_rate).max(1.0);
+    v.release_factor = 10f32.powf(-5.0 / samples as f32);
+}
+
+/// Steps the volume envelope by one sample. Returns false once the voice is silent.
+fn advance_envelope(v: &mut Voice, r: &Region, dt: f64) -> bool {
+    v.stage_time += dt;
+
+    match v.stage {
+        Stage::Delay => {
+            v.level = 0.0;
+            if v.stage_time >= r.delay {
+                v.stage = Stage::Attack;
+                v.stage_time = 0.0;
+            }
+        }
+        Stage::Attack => {
+            v.level = if r.attack > 0.0 { (v.stage_time / r.attack).min(1.0) as f32 } else { 1.0 };
+            if v.stage_time >= r.attack {
+                v.stage = Stage::Hold;
+                v.stage_time = 0.0;
+            }
+        }
+        Stage::Hold => {
+            v.level = 1.0;
+            if v.stage_time >= r.hold {
+                v.stage = Stage::Decay;
+                v.stage_time = 0.0;
+            }
+        }
+        Stage::Decay => {
+            // The decay time is the time to fall by 100dB, so this is linear in decibels.
+            v.level = if r.decay > 0.0 { 10f32.powf((-5.0 * v.stage_time / r.decay) as f32) } else { 0.0 };
+            if v.level <= r.sustain {
+                v.level = r.sustain;
+                v.stage = Stage::Sustain;
+            }
+        }
+        Stage::Sustain => {
+            if v.level < SILENCE {
+                v.stage = Stage::Finished;
+            }
+        }
+        Stage::Release => {
+            v.level *= v.release_factor;
+            if v.level < SILENCE {
+                v.stage = Stage::Finished;
+            }
+        }
+        Stage::Finished => {}
+    }
+
+    v.stage != Stage::Finished
+}
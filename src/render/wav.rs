@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+// The header is followed by a JUNK chunk the size of a ds64 chunk, which `finish`
+// turns into one when the file is too big for plain RIFF sizes.
+const DS64_SIZE: u32 = 28;
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DS64_OFFSET: u64 = 12;
+const DATA_SIZE_OFFSET: u64 = 76;
+const HEADER_SIZE: u64 = 80;
+
+/// Writes 16-bit stereo PCM WAV files. The header sizes are filled in by `finish`,
+/// which switches to RF64 for files past 4 GB.
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_len: u64,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let channels: u16 = 2;
+        let bits: u16 = 16;
+        let block_align = channels * bits / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"JUNK")?;
+        writer.write_all(&DS64_SIZE.to_le_bytes())?;
+        writer.write_all(&[0; DS64_SIZE as usize])?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { writer, data_len: 0 })
+    }
+
+    /// Writes interleaved stereo samples, clipping them to -1.0..1.0.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(samples.len() * 2);
+        for s in samples {
+            let v = (s.clamp(-1.0, 1.0) * 32767.0) as i16;
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        self.writer.write_all(&buf)?;
+        self.data_len += buf.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let data_len = self.data_len;
+        let riff_len = HEADER_SIZE - 8 + data_len;
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+
+        if riff_len <= u32::MAX as u64 {
+            file.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+            file.write_all(&(riff_len as u32).to_le_bytes())?;
+            file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+            file.write_all(&(data_len as u32).to_le_bytes())?;
+        } else {
+            // RF64 keeps the real sizes in the ds64 chunk and marks the 32 bit ones as unused.
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(b"RF64")?;
+            file.write_all(&u32::MAX.to_le_bytes())?;
+            file.seek(SeekFrom::Start(DS64_OFFSET))?;
+            file.write_all(b"ds64")?;
+            file.write_all(&DS64_SIZE.to_le_bytes())?;
+            file.write_all(&riff_len.to_le_bytes())?;
+            file.write_all(&data_len.to_le_bytes())?;
+            file.write_all(&(data_len / 4).to_le_bytes())?;
+            file.write_all(&0u32.to_le_bytes())?;
+            file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+            file.write_all(&u32::MAX.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
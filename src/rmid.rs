@@ -0,0 +1,95 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Metadata from the INFO list of an RMID file.
+#[derive(Default)]
+pub struct RmidInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// Whether `bytes` start like a RIFF RMID file, a standard MIDI file wrapped in a RIFF container.
+pub fn is_rmid(bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"RMID"
+}
+
+/// The chunks of a RIFF form, after its 12 byte header.
+struct Chunks<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        let header = self.bytes.get(self.pos..self.pos + 8)?;
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let start = self.pos + 8;
+        // A truncated chunk is used as far as it goes, like truncated tracks.
+        let end = start.saturating_add(len).min(self.bytes.len());
+        // Chunks are padded to an even length.
+        self.pos = end + (len & 1);
+        Some((&header[..4], &self.bytes[start..end]))
+    }
+}
+
+fn chunks(bytes: &[u8]) -> Chunks<'_> {
+    Chunks { bytes, pos: 0 }
+}
+
+/// Finds the standard MIDI file in the `data` chunk of an RMID file. Anything else in
+/// it, like an embedded DLS bank, is ignored.
+pub fn smf_data(bytes: &[u8]) -> Result<&[u8], String> {
+    chunks(&bytes[12..])
+        .find(|(id, _)| *id == b"data")
+        .map(|(_, data)| data)
+        .ok_or_else(|| "RMID file without a data chunk".to_string())
+}
+
+fn info_string(data: &[u8]) -> Option<String> {
+    // Strings are zero terminated, and usually ASCII.
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let text = String::from_utf8_lossy(&data[..end]).trim().to_string();
+    if text.is_empty() { None } else { Some(text) }
+}
+
+/// Reads the INFO metadata of an RMID file without loading the MIDI data.
+/// `None` if the file isn't an RMID file.
+pub fn read_info(path: &str) -> io::Result<Option<RmidInfo>> {
+    let mut file = File::open(path)?;
+    let mut header = [0; 12];
+    if file.read_exact(&mut header).is_err() || !is_rmid(&header) {
+        return Ok(None);
+    }
+
+    let mut info = RmidInfo::default();
+    let mut chunk_header = [0; 12];
+    // Every chunk but INFO lists is skipped, so the MIDI data and DLS banks are never read.
+    while file.read_exact(&mut chunk_header[..8]).is_ok() {
+        let len = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as u64;
+        let padded = len + (len & 1);
+
+        if &chunk_header[..4] == b"LIST" && len >= 4 {
+            file.read_exact(&mut chunk_header[8..12])?;
+            if &chunk_header[8..12] == b"INFO" {
+                let mut list = Vec::new();
+                (&mut file).take(len - 4).read_to_end(&mut list)?;
+                for (id, data) in chunks(&list) {
+                    match id {
+                        b"INAM" => info.title = info_string(data),
+                        b"IART" => info.artist = info_string(data),
+                        _ => {}
+                    }
+                }
+                file.seek(SeekFrom::Current((padded - len) as i64))?;
+            } else {
+                file.seek(SeekFrom::Current(padded as i64 - 4))?;
+            }
+        } else {
+            file.seek(SeekFrom::Current(padded as i64))?;
+        }
+    }
+    Ok(Some(info))
+}
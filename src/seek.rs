@@ -0,0 +1,463 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use crate::loader::{LoadError, REPORT_INTERVAL};
+use crate::output::OutputBackend;
+use crate::progress::{LoadProgress, Phase};
+use crate::stream::{Chunk, ChunkReceiver};
+use crate::timeline::{Message, Timeline, TimelineEvent};
+
+/// Marks controllers that haven't been set, real values are 7 bit.
+const UNSET: u8 = 0xFF;
+
+/// Seconds of the song between two snapshots of a [`SeekIndex`]. A seek never has to
+/// play through more than this.
+const SNAPSHOT_INTERVAL: f64 = 1.0;
+
+#[derive(Clone, Copy)]
+struct ChannelControls {
+    /// Controllers 0 to 119. The rest are channel mode messages, which aren't state.
+    controllers: [u8; 120],
+    program: u8,
+    pressure: u8,
+    pitch_bend: u16,
+    /// Set with RPN 0, so bends after a seek go as far as before.
+    bend_range: u8,
+    /// The RPN data entry goes to, as selected by controllers 101 and 100.
+    rpn: (u8, u8),
+}
+
+impl ChannelControls {
+    const BYTES: usize = 127;
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.controllers);
+        out.extend_from_slice(&[self.program, self.pressure]);
+        out.extend_from_slice(&self.pitch_bend.to_le_bytes());
+        out.extend_from_slice(&[self.bend_range, self.rpn.0, self.rpn.1]);
+    }
+
+    fn read_from(bytes: &[u8]) -> ChannelControls {
+        let mut controllers = [0; 120];
+        controllers.copy_from_slice(&bytes[..120]);
+        ChannelControls {
+            controllers,
+            program: bytes[120],
+            pressure: bytes[121],
+            pitch_bend: u16::from_le_bytes([bytes[122], bytes[123]]),
+            bend_range: bytes[124],
+            rpn: (bytes[125], bytes[126]),
+        }
+    }
+}
+
+/// Splits the first `n` bytes off `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if bytes.len() < n {
+        return None;
+    }
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Some(taken)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(take(bytes, 8)?.try_into().ok()?))
+}
+
+impl Default for ChannelControls {
+    fn default() -> ChannelControls {
+        ChannelControls {
+            controllers: [UNSET; 120],
+            program: UNSET,
+            pressure: UNSET,
+            pitch_bend: u16::MAX,
+            bend_range: UNSET,
+            rpn: (127, 127),
+        }
+    }
+}
+
+/// What playing every event up to some point leaves behind: the controllers of every
+/// channel on every port, and which keys are held.
+///
+/// Seeking rebuilds this for the target time, so playback there sounds and looks the
+/// same as if it had played through.
+#[derive(Clone)]
+pub struct PlayState {
+    port: u8,
+    /// Indexed by port, only as long as the highest port used.
+    controls: Vec<[ChannelControls; 16]>,
+    held: [[u16; 128]; 16],
+    /// The channel of the last note on for each key, which is the color it shows in.
+    last_channel: [u8; 128],
+}
+
+impl Default for PlayState {
+    fn default() -> PlayState {
+        PlayState {
+            port: 0,
+            controls: Vec::new(),
+            held: [[0; 128]; 16],
+            last_channel: [0; 128],
+        }
+    }
+}
+
+impl PlayState {
+    pub fn new() -> PlayState {
+        PlayState::default()
+    }
+
+    pub fn apply(&mut self, message: &Message) {
+        match *message {
+            Message::Short(message) => self.apply_short(message),
+            Message::Port(port) => self.port = port,
+            Message::SysEx(_) => {}
+        }
+    }
+
+    fn apply_short(&mut self, message: u32) {
+        let channel = (message & 0x0F) as usize;
+        let data1 = ((message >> 8) & 0x7F) as u8;
+        let data2 = ((message >> 16) & 0x7F) as u8;
+
+        match message & 0xF0 {
+            0x90 => {
+                let held = &mut self.held[channel][data1 as usize];
+                *held = held.saturating_add(1);
+                self.last_channel[data1 as usize] = channel as u8;
+            }
+            0x80 => {
+                let held = &mut self.held[channel][data1 as usize];
+                *held = held.saturating_sub(1);
+            }
+            0xB0 => {
+                let controls = self.channel_controls(channel);
+                match data1 {
+                    101 => controls.rpn.0 = data2,
+                    100 => controls.rpn.1 = data2,
+                    6 if controls.rpn == (0, 0) => controls.bend_range = data2,
+                    // Data entry only means something with the RPN it went to.
+                    6 | 38 | 96..=99 => {}
+                    0..=119 => controls.controllers[data1 as usize] = data2,
+                    // Reset All Controllers, which puts back the defaults a reset output has anyway.
+                    121 => {
+                        for controller in [1, 11, 64, 65, 66, 67, 68, 69].iter() {
+                            controls.controllers[*controller] = UNSET;
+                        }
+                        controls.pressure = UNSET;
+                        controls.pitch_bend = u16::MAX;
+                        controls.rpn = (127, 127);
+                    }
+                    _ => {}
+                }
+            }
+            0xC0 => self.channel_controls(channel).program = data1,
+            0xD0 => self.channel_controls(channel).pressure = data1,
+            0xE0 => self.channel_controls(channel).pitch_bend = data1 as u16 | (data2 as u16) << 7,
+            _ => {}
+        }
+    }
+
+    fn channel_controls(&mut self, channel: usize) -> &mut ChannelControls {
+        let port = self.port as usize;
+        if self.controls.len() <= port {
+            self.controls.resize(port + 1, Default::default());
+        }
+        &mut self.controls[port][channel]
+    }
+
+    /// Sends the controller state to an output that was just reset. Notes aren't sent,
+    /// the ones held at this point start sounding with the next note on.
+    pub fn replay<O: OutputBackend + ?Sized>(&self, output: &mut O) {
+        for (port, channels) in self.controls.iter().enumerate() {
+            output.set_port(port as u8);
+            for (channel, controls) in channels.iter().enumerate() {
+                let channel = channel as u32;
+                if controls.bend_range != UNSET {
+                    for (controller, value) in [(101, 0), (100, 0), (6, controls.bend_range as u32), (101, 127), (100, 127)].iter() {
+                        output.send_short(0xB0 | channel | controller << 8 | value << 16);
+                    }
+                }
+                // Bank select comes before the program change this way.
+                for (controller, value) in controls.controllers.iter().enumerate() {
+                    if *value != UNSET {
+                        output.send_short(0xB0 | channel | (controller as u32) << 8 | (*value as u32) << 16);
+                    }
+                }
+                if controls.program != UNSET {
+                    output.send_short(0xC0 | channel | (controls.program as u32) << 8);
+                }
+                if controls.pressure != UNSET {
+                    output.send_short(0xD0 | channel | (controls.pressure as u32) << 8);
+                }
+                if controls.pitch_bend != u16::MAX {
+                    let bend = controls.pitch_bend as u32;
+                    output.send_short(0xE0 | channel | (bend & 0x7F) << 8 | (bend >> 7) << 16);
+                }
+            }
+        }
+        output.set_port(self.port);
+    }
+
+    /// The MIDI port the following events play on.
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    /// How many notes hold `key` down on `channel`.
+    pub fn held(&self, channel: usize, key: usize) -> u16 {
+        self.held[channel][key]
+    }
+
+    /// The channel that pressed `key` last.
+    pub fn last_channel(&self, key: usize) -> usize {
+        self.last_channel[key] as usize
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(self.port);
+        out.extend_from_slice(&(self.controls.len() as u16).to_le_bytes());
+        for controls in self.controls.iter().flatten() {
+            controls.write_to(out);
+        }
+        for held in self.held.iter().flatten() {
+            out.extend_from_slice(&held.to_le_bytes());
+        }
+        out.extend_from_slice(&self.last_channel);
+    }
+
+    fn read_from(bytes: &mut &[u8]) -> Option<PlayState> {
+        let mut state = PlayState::new();
+        state.port = take(bytes, 1)?[0];
+
+        let ports = u16::from_le_bytes(take(bytes, 2)?.try_into().ok()?) as usize;
+        for _ in 0..ports {
+            let mut channels = [ChannelControls::default(); 16];
+            for controls in channels.iter_mut() {
+                *controls = ChannelControls::read_from(take(bytes, ChannelControls::BYTES)?);
+            }
+            state.controls.push(channels);
+        }
+
+        for held in state.held.iter_mut().flatten() {
+            *held = u16::from_le_bytes(take(bytes, 2)?.try_into().ok()?);
+        }
+        state.last_channel.copy_from_slice(take(bytes, 128)?);
+        if state.last_channel.iter().any(|c| *c >= 16) {
+            return None;
+        }
+        Some(state)
+    }
+}
+
+/// Where a [`Playhead`] would be after seeking to some time from the start.
+struct Snapshot {
+    /// The first event at or after the time.
+    index: usize,
+    next_meta: usize,
+    /// The time of the event before `index`.
+    time: f64,
+    state: PlayState,
+}
+
+/// The play state every [`SNAPSHOT_INTERVAL`] seconds of a loaded song, built once after
+/// loading so seeks start from the closest snapshot instead of the beginning.
+pub struct SeekIndex {
+    /// Snapshot `n` is for `n * SNAPSHOT_INTERVAL` seconds. The last one is past the end.
+    snapshots: Vec<Snapshot>,
+}
+
+impl SeekIndex {
+    pub fn build(timeline: &Timeline, progress: &LoadProgress) -> Result<SeekIndex, LoadError> {
+        progress.start(Phase::Indexing, timeline.len() as u64);
+
+        let mut snapshots = Vec::new();
+        let mut state = PlayState::new();
+        let mut next_meta = 0;
+        let mut time = 0.0;
+
+        for index in 0..timeline.len() {
+            // A long gap between events gets a snapshot for every interval in it.
+            let event_time = time + timeline.delta(index);
+            while snapshots.len() as f64 * SNAPSHOT_INTERVAL <= event_time {
+                snapshots.push(Snapshot { index, next_meta, time, state: state.clone() });
+            }
+
+            let event = timeline.event(index, &mut next_meta);
+            time = event_time;
+            if let Some(message) = &event.message {
+                state.apply(message);
+            }
+
+            if index as u64 & (REPORT_INTERVAL - 1) == 0 {
+                if progress.is_cancelled() {
+                    return Err(LoadError::Cancelled);
+                }
+                progress.set(index as u64);
+            }
+        }
+        snapshots.push(Snapshot { index: timeline.len(), next_meta, time, state });
+        progress.set(timeline.len() as u64);
+
+        Ok(SeekIndex { snapshots })
+    }
+
+    /// The snapshots as bytes, for the event cache.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.snapshots.len() as u64).to_le_bytes());
+        for snapshot in &self.snapshots {
+            out.extend_from_slice(&(snapshot.index as u64).to_le_bytes());
+            out.extend_from_slice(&(snapshot.next_meta as u64).to_le_bytes());
+            out.extend_from_slice(&snapshot.time.to_bits().to_le_bytes());
+            snapshot.state.write_to(&mut out);
+        }
+        out
+    }
+
+    /// Reads snapshots written by [`SeekIndex::to_bytes`]. None unless they fill `bytes`
+    /// exactly and every one points into a timeline of `len` events and `meta_len` SysEx messages.
+    pub fn from_bytes(mut bytes: &[u8], len: usize, meta_len: usize) -> Option<SeekIndex> {
+        let count = take_u64(&mut bytes)? as usize;
+        // Every snapshot takes well over 8 bytes, so a broken count can't allocate much.
+        let mut snapshots = Vec::with_capacity(count.min(bytes.len() / 8));
+        for _ in 0..count {
+            let index = take_u64(&mut bytes)? as usize;
+            let next_meta = take_u64(&mut bytes)? as usize;
+            let time = f64::from_bits(take_u64(&mut bytes)?);
+            let state = PlayState::read_from(&mut bytes)?;
+            if index > len || next_meta > meta_len {
+                return None;
+            }
+            snapshots.push(Snapshot { index, next_meta, time, state });
+        }
+
+        if snapshots.is_empty() || !bytes.is_empty() {
+            return None;
+        }
+        Some(SeekIndex { snapshots })
+    }
+
+    /// How long the song is, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.snapshots[self.snapshots.len() - 1].time
+    }
+
+    /// The last snapshot at or before `target` seconds.
+    fn snapshot(&self, target: f64) -> &Snapshot {
+        let n = (target.max(0.0) / SNAPSHOT_INTERVAL) as usize;
+        &self.snapshots[n.min(self.snapshots.len() - 1)]
+    }
+}
+
+/// Hands out the events a playback thread receives, and moves to other times in them.
+pub struct Playhead {
+    receiver: ChunkReceiver,
+    chunks: Vec<Chunk>,
+    seek_index: Option<Arc<SeekIndex>>,
+    chunk: usize,
+    index: usize,
+    next_meta: usize,
+    time: f64,
+}
+
+impl Playhead {
+    /// A `seek_index` is for songs loaded as one preloaded chunk, which then stays around
+    /// so playback can go back. Without one, chunks are dropped once they played to keep
+    /// memory down while streaming, and seeks only go forward.
+    pub fn new(receiver: ChunkReceiver, seek_index: Option<Arc<SeekIndex>>) -> Playhead {
+        Playhead {
+            receiver,
+            chunks: Vec::new(),
+            seek_index,
+            chunk: 0,
+            index: 0,
+            next_meta: 0,
+            time: 0.0,
+        }
+    }
+
+    /// Why the events stopped before the end of the song, if they did.
+    pub fn error(&self) -> Option<&str> {
+        self.receiver.error()
+    }
+
+    /// The time of the last event handed out, in seconds.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Makes sure `chunk` and `index` point at an event. False at the end of the song.
+    fn load(&mut self) -> bool {
+        loop {
+            if self.chunk < self.chunks.len() {
+                if self.index < self.chunks[self.chunk].len() {
+                    return true;
+                }
+                self.chunk += 1;
+                self.index = 0;
+                self.next_meta = 0;
+                continue;
+            }
+
+            match self.receiver.recv() {
+                Some(chunk) => {
+                    if self.seek_index.is_none() {
+                        self.chunks.clear();
+                        self.chunk = 0;
+                    }
+                    self.chunks.push(chunk);
+                }
+                None => return false,
+            }
+        }
+    }
+
+    /// The time of the next event, without moving on to it.
+    pub fn next_time(&mut self) -> Option<f64> {
+        if !self.load() {
+            return None;
+        }
+        Some(self.time + self.chunks[self.chunk].delta(self.index))
+    }
+
+    /// Moves on to the next event and hands it out.
+    pub fn advance(&mut self) -> Option<TimelineEvent<'_>> {
+        if !self.load() {
+            return None;
+        }
+        let event = self.chunks[self.chunk].event(self.index, &mut self.next_meta);
+        self.index += 1;
+        self.time += event.delta;
+        Some(event)
+    }
+
+    /// Moves to the first event at or after `target` seconds. The events skipped on the
+    /// way are applied to `state` instead of being played.
+    ///
+    /// With a seek index, this starts from the closest snapshot when going back or when
+    /// the snapshot is further ahead. Without one, going back does nothing.
+    pub fn seek(&mut self, target: f64, state: &mut PlayState) {
+        if let Some(seek_index) = &self.seek_index {
+            let snapshot = seek_index.snapshot(target);
+            if target < self.time || snapshot.index > self.index {
+                self.chunk = 0;
+                self.index = snapshot.index;
+                self.next_meta = snapshot.next_meta;
+                self.time = snapshot.time;
+                *state = snapshot.state.clone();
+            }
+        }
+
+        while let Some(time) = self.next_time() {
+            if time >= target {
+                break;
+            }
+            if let Some(message) = self.advance().and_then(|e| e.message) {
+                state.apply(&message);
+            }
+        }
+    }
+}
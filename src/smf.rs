@@ -0,0 +1,320 @@
+use std::convert::TryInto;
+
+use crate::rmid;
+
+/// An event read from a track. Meta events the player doesn't use are skipped.
+#[derive(Clone, Copy)]
+pub enum TrackEvent<'a> {
+    /// A channel message packed as `status | data1 << 8 | data2 << 16`.
+    Short(u32),
+    /// The bytes of a SysEx message after the 0xF0, usually ending with 0xF7.
+    SysEx(&'a [u8]),
+    /// Microseconds per quarter note (FF 51).
+    Tempo(u32),
+    /// The MIDI port the rest of the track plays on (FF 21).
+    Port(u8),
+}
+
+/// How a file's ticks relate to time.
+#[derive(Clone, Copy)]
+pub enum Division {
+    /// Ticks per quarter note, so how long a tick is follows the tempo.
+    Ppq(u16),
+    /// SMPTE timecode, a fixed number of ticks per frame. Tempo changes don't apply.
+    Smpte { frames_per_second: f64, ticks_per_frame: u8 },
+}
+
+impl Division {
+    fn from_header(division: u16) -> Result<Division, String> {
+        if division & 0x8000 == 0 {
+            if division == 0 {
+                return Err("the time division is 0".to_string());
+            }
+            return Ok(Division::Ppq(division));
+        }
+
+        // The upper byte is the frame rate as a negative number.
+        let frames_per_second = match (division >> 8) as u8 {
+            0xE8 => 24.0,
+            0xE7 => 25.0,
+            // Drop frame timecode, which runs at 29.97 frames per second.
+            0xE3 => 30000.0 / 1001.0,
+            0xE2 => 30.0,
+            other => return Err(format!("unknown SMPTE frame rate byte 0x{:02X}", other)),
+        };
+        let ticks_per_frame = division as u8;
+        if ticks_per_frame == 0 {
+            return Err("the time division is 0".to_string());
+        }
+        Ok(Division::Smpte { frames_per_second, ticks_per_frame })
+    }
+}
+
+/// A standard MIDI file that is already in memory, or the one inside an RMID file.
+/// Only the header is parsed up front.
+pub struct Smf<'a> {
+    pub division: Division,
+    pub tracks: Vec<&'a [u8]>,
+}
+
+impl<'a> Smf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Smf<'a>, String> {
+        // RMID files wrap a standard MIDI file in a RIFF container.
+        let bytes = if rmid::is_rmid(bytes) { rmid::smf_data(bytes)? } else { bytes };
+
+        if bytes.len() < 14 || &bytes[..4] != b"MThd" {
+            return Err("not a MIDI file (no MThd header)".to_string());
+        }
+
+        let header_len = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let track_count = u16::from_be_bytes([bytes[10], bytes[11]]) as usize;
+        let division = Division::from_header(u16::from_be_bytes([bytes[12], bytes[13]]))?;
+
+        // Chunks that aren't MTrk are skipped, as the spec asks. Black MIDIs often have
+        // a wrong track count in the header, so every track in the file is read.
+        let mut tracks = Vec::with_capacity(track_count);
+        let mut pos = 8usize.saturating_add(header_len);
+        while pos + 8 <= bytes.len() {
+            let id = &bytes[pos..pos + 4];
+            let len = u32::from_be_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let start = pos + 8;
+            // A truncated last track is played as far as it goes.
+            let end = start.saturating_add(len).min(bytes.len());
+            if id == b"MTrk" {
+                tracks.push(&bytes[start..end]);
+            }
+            pos = end;
+        }
+
+        Ok(Smf {
+            division,
+            tracks,
+        })
+    }
+}
+
+/// Reads one track's events along with their absolute tick.
+///
+/// Broken data ends the track instead of failing the whole file.
+pub struct TrackReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    tick: u64,
+    running_status: u8,
+}
+
+impl<'a> TrackReader<'a> {
+    pub fn new(data: &'a [u8]) -> TrackReader<'a> {
+        TrackReader {
+            data,
+            pos: 0,
+            tick: 0,
+            running_status: 0,
+        }
+    }
+
+    /// How many bytes of the track have been read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn var_length(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            value = (value << 7) | (b & 0x7F) as u64;
+            if b & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn bytes(&mut self, len: u64) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len as usize)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+}
+
+impl<'a> Iterator for TrackReader<'a> {
+    type Item = (u64, TrackEvent<'a>);
+
+    fn next(&mut self) -> Option<(u64, TrackEvent<'a>)> {
+        loop {
+            self.tick += self.var_length()?;
+
+            let mut status = *self.data.get(self.pos)?;
+            if status & 0x80 != 0 {
+                self.pos += 1;
+            } else if self.running_status != 0 {
+                status = self.running_status;
+            } else {
+                return None;
+            }
+
+            // SysEx and meta events cancel running status, as the spec says, so the data
+            // bytes of a broken file after one aren't read as notes.
+            if status >= 0xF0 {
+                self.running_status = 0;
+            }
+
+            match status {
+                0x80..=0xEF => {
+                    self.running_status = status;
+                    let data1 = self.byte()? & 0x7F;
+                    let data2 = match status & 0xF0 {
+                        0xC0 | 0xD0 => 0,
+                        _ => self.byte()? & 0x7F,
+                    };
+
+                    // Note on with velocity 0 is a note off.
+                    let status = if status & 0xF0 == 0x90 && data2 == 0 { 0x80 | (status & 0x0F) } else { status };
+                    let message = status as u32 | (data1 as u32) << 8 | (data2 as u32) << 16;
+                    return Some((self.tick, TrackEvent::Short(message)));
+                }
+                0xF0 => {
+                    let len = self.var_length()?;
+                    let data = self.bytes(len)?;
+                    return Some((self.tick, TrackEvent::SysEx(data)));
+                }
+                0xF7 => {
+                    // Escaped data (SysEx continuations and the like), not sent.
+                    let len = self.var_length()?;
+                    self.bytes(len)?;
+                }
+                0xFF => {
+                    let kind = self.byte()?;
+                    let len = self.var_length()?;
+                    let data = self.bytes(len)?;
+                    match kind {
+                        0x2F => return None,
+                        0x51 if len >= 3 => {
+                            let tempo = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+                            return Some((self.tick, TrackEvent::Tempo(tempo)));
+                        }
+                        0x21 if len >= 1 => return Some((self.tick, TrackEvent::Port(data[0]))),
+                        _ => {}
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06\0\x01".to_vec();
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+        bytes
+    }
+
+    fn events(track: &[u8]) -> Vec<(u64, String)> {
+        TrackReader::new(track)
+            .map(|(tick, event)| {
+                let event = match event {
+                    TrackEvent::Short(message) => format!("short {:06x}", message),
+                    TrackEvent::SysEx(data) => format!("sysex {:02x?}", data),
+                    TrackEvent::Tempo(tempo) => format!("tempo {}", tempo),
+                    TrackEvent::Port(port) => format!("port {}", port),
+                };
+                (tick, event)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_every_kind_of_event() {
+        let track = [
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // tempo 500000
+            0x00, 0xFF, 0x21, 0x01, 0x02, // port 2
+            0x00, 0xFF, 0x03, 0x02, b'h', b'i', // track name, skipped
+            0x00, 0xC3, 0x05, // program change, one data byte
+            0x60, 0x93, 0x3C, 0x64, // note on
+            0x81, 0x00, 0x3C, 0x00, // running status, velocity 0 is a note off
+            0x00, 0xF0, 0x03, 0x41, 0x10, 0xF7, // sysex
+            0x00, 0xF7, 0x01, 0x42, // escaped data, skipped
+            0x00, 0xFF, 0x2F, 0x00, // end of track
+            0x00, 0x90, 0x40, 0x40, // after the end, never read
+        ];
+        assert_eq!(
+            events(&track),
+            vec![
+                (0, "tempo 500000".to_string()),
+                (0, "port 2".to_string()),
+                (0, "short 0005c3".to_string()),
+                (0x60, "short 643c93".to_string()),
+                (0xE0, "short 003c83".to_string()),
+                (0xE0, "sysex [41, 10, f7]".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn meta_and_sysex_events_cancel_running_status() {
+        let after_meta = [0x00, 0x90, 0x3C, 0x64, 0x00, 0xFF, 0x01, 0x00, 0x00, 0x3C, 0x00];
+        assert_eq!(events(&after_meta), vec![(0, "short 643c90".to_string())]);
+
+        let after_sysex = [0x00, 0x90, 0x3C, 0x64, 0x00, 0xF0, 0x01, 0xF7, 0x00, 0x3C, 0x00];
+        assert_eq!(events(&after_sysex), vec![(0, "short 643c90".to_string()), (0, "sysex [f7]".to_string())]);
+
+        let without_status = [0x00, 0x3C, 0x64];
+        assert!(events(&without_status).is_empty());
+    }
+
+    #[test]
+    fn broken_data_ends_the_track() {
+        // The var length runs past 4 bytes, then a note is cut short.
+        assert!(events(&[0x80, 0x80, 0x80, 0x80, 0x00, 0x90, 0x3C, 0x64]).is_empty());
+        assert_eq!(events(&[0x00, 0x90, 0x3C, 0x64, 0x00, 0x3C]), vec![(0, "short 643c90".to_string())]);
+        // Undefined status bytes aren't guessed at.
+        assert!(events(&[0x00, 0xF4, 0x00]).is_empty());
+    }
+
+    #[test]
+    fn parses_headers_and_finds_tracks() {
+        let bytes = file(480, &[&[0x00, 0xFF, 0x2F, 0x00], &[0x00, 0x90, 0x3C, 0x64]]);
+        let smf = Smf::parse(&bytes).unwrap();
+        assert!(matches!(smf.division, Division::Ppq(480)));
+        assert_eq!(smf.tracks, vec![&[0x00, 0xFF, 0x2F, 0x00][..], &[0x00, 0x90, 0x3C, 0x64][..]]);
+
+        // Unknown chunks are skipped, the track count is ignored and a cut off track is kept.
+        let mut bytes = file(96, &[&[0x00, 0x90, 0x3C, 0x64]]);
+        bytes[10..12].copy_from_slice(&5u16.to_be_bytes());
+        bytes.extend_from_slice(b"XFIH\0\0\0\x02ab");
+        bytes.extend_from_slice(b"MTrk\0\0\0\x10\x00\x80");
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.tracks, vec![&[0x00, 0x90, 0x3C, 0x64][..], &[0x00, 0x80][..]]);
+
+        let bytes = file(0xE250, &[]);
+        match Smf::parse(&bytes).unwrap().division {
+            Division::Smpte { frames_per_second, ticks_per_frame } => assert_eq!((frames_per_second, ticks_per_frame), (30.0, 80)),
+            Division::Ppq(_) => panic!("read SMPTE as PPQ"),
+        }
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_midi_file() {
+        assert!(Smf::parse(b"RIFX not a midi file").is_err());
+        assert!(Smf::parse(&file(0, &[])).is_err());
+        assert!(Smf::parse(&file(0xE100, &[])).is_err());
+        assert!(Smf::parse(&file(0xE800, &[])).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::mem;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::smf::TrackEvent;
+use crate::timeline::Timeline;
+
+/// Most events per chunk handed to the consumers.
+const CHUNK_SIZE: usize = 1 << 14;
+
+/// Seconds of the song the producer can get ahead of the slowest consumer. This is
+/// what keeps memory constant while streaming, the producer blocks once it's this far ahead.
+const SECONDS_AHEAD: f64 = 4.0;
+
+/// Most seconds of the song per chunk, so a sparse song doesn't go far past `SECONDS_AHEAD`
+/// in one chunk.
+const CHUNK_SECONDS: f64 = SECONDS_AHEAD / 8.0;
+
+/// Chunks each consumer can have waiting, so a dense song can't be `SECONDS_AHEAD`
+/// worth of full chunks.
+const CHUNKS_AHEAD: usize = 64;
+
+/// A run of consecutive events, shared by every consumer.
+pub type Chunk = Arc<Timeline>;
+
+enum Item {
+    /// A chunk and the song time it starts at.
+    Chunk(f64, Chunk),
+    /// The producer stopped before the end of the song.
+    Failed(String),
+}
+
+/// Where in the song each consumer is, as the start of the chunk it's playing.
+/// Consumers that went away count as infinitely far ahead.
+struct Positions {
+    times: Mutex<Vec<f64>>,
+    changed: Condvar,
+}
+
+/// Collects events into chunks and hands each chunk to every consumer.
+pub struct ChunkSender {
+    senders: Vec<SyncSender<Item>>,
+    positions: Arc<Positions>,
+    chunk: Timeline,
+    /// Song time at the start and end of `chunk`.
+    start: f64,
+    end: f64,
+    /// Song time at the start of the chunk sent last.
+    last_sent: f64,
+}
+
+/// Receives the chunks of a stream, or of a preloaded timeline.
+pub struct ChunkReceiver {
+    receiver: Receiver<Item>,
+    positions: Option<(Arc<Positions>, usize)>,
+    error: Option<String>,
+}
+
+/// Creates a bounded stream with `consumers` receivers that all get the same chunks, in order.
+pub fn chunked(consumers: usize) -> (ChunkSender, Vec<ChunkReceiver>) {
+    let positions = Arc::new(Positions {
+        times: Mutex::new(vec![0.0; consumers]),
+        changed: Condvar::new(),
+    });
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..consumers).map(|_| sync_channel(CHUNKS_AHEAD)).unzip();
+    let receivers = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(consumer, receiver)| ChunkReceiver {
+            receiver,
+            positions: Some((Arc::clone(&positions), consumer)),
+            error: None,
+        })
+        .collect();
+    let sender = ChunkSender {
+        senders,
+        positions,
+        chunk: Timeline::with_capacity(CHUNK_SIZE),
+        start: 0.0,
+        end: 0.0,
+        last_sent: 0.0,
+    };
+    (sender, receivers)
+}
+
+/// Hands a timeline that is already in memory to `consumers` receivers as a single chunk.
+pub fn preloaded(timeline: Timeline, consumers: usize) -> Vec<ChunkReceiver> {
+    let timeline = Arc::new(timeline);
+    (0..consumers)
+        .map(|_| {
+            let (sender, receiver) = sync_channel(1);
+            sender.send(Item::Chunk(0.0, Arc::clone(&timeline))).ok();
+            ChunkReceiver { receiver, positions: None, error: None }
+        })
+        .collect()
+}
+
+impl ChunkSender {
+    /// Adds an event, and sends the chunk once it's full. Blocks while the consumers are
+    /// too far behind. Returns false once every consumer has gone away.
+    pub fn push_event(&mut self, delta: f64, event: TrackEvent) -> bool {
+        self.chunk.push_event(delta, event);
+        self.end += delta;
+        if self.chunk.len() >= CHUNK_SIZE || self.end - self.start >= CHUNK_SECONDS {
+            self.flush()
+        } else {
+            !self.senders.is_empty()
+        }
+    }
+
+    /// Sends the events collected so far without waiting for the chunk to fill up.
+    pub fn flush(&mut self) -> bool {
+        if !self.chunk.is_empty() {
+            self.wait_for_consumers();
+            let chunk = Arc::new(mem::replace(&mut self.chunk, Timeline::with_capacity(CHUNK_SIZE)));
+            let start = self.start;
+            self.senders.retain(|s| s.send(Item::Chunk(start, Arc::clone(&chunk))).is_ok());
+            self.last_sent = start;
+            self.start = self.end;
+        }
+        !self.senders.is_empty()
+    }
+
+    /// Ends the stream early, handing `error` to the consumers once they played everything before it.
+    pub fn fail(mut self, error: String) {
+        self.flush();
+        for sender in self.senders.drain(..) {
+            sender.send(Item::Failed(error.clone())).ok();
+        }
+    }
+
+    /// Waits until the chunk about to be sent isn't too far ahead of any consumer. A consumer
+    /// that has everything sent so far isn't waited for, after a long pause in the song.
+    fn wait_for_consumers(&self) {
+        let mut times = self.positions.times.lock().unwrap();
+        while times.iter().any(|t| self.start - t > SECONDS_AHEAD && *t < self.last_sent) {
+            times = self.positions.changed.wait(times).unwrap();
+        }
+    }
+}
+
+impl Drop for ChunkSender {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl ChunkReceiver {
+    /// The next chunk, or None at the end of the stream. Check [`ChunkReceiver::error`]
+    /// for whether it ended early.
+    pub fn recv(&mut self) -> Option<Chunk> {
+        match self.receiver.recv() {
+            Ok(Item::Chunk(start, chunk)) => {
+                self.set_position(start);
+                Some(chunk)
+            }
+            Ok(Item::Failed(error)) => {
+                self.error = Some(error);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Why the producer stopped before the end of the song, if it did.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    fn set_position(&self, time: f64) {
+        if let Some((positions, consumer)) = &self.positions {
+            positions.times.lock().unwrap()[*consumer] = time;
+            positions.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for ChunkReceiver {
+    fn drop(&mut self) {
+        self.set_position(f64::INFINITY);
+    }
+}
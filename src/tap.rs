@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Writes every event the visualizer processes as one JSON object per line, for external tools.
+///
+/// `wall` is the real time since playback started, `time` the scheduled playback time,
+/// both in seconds.
+pub struct EventTap {
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl EventTap {
+    /// Opens the tap on a file, or on stdout if `path` is `-`.
+    pub fn open(path: &str) -> io::Result<EventTap> {
+        let sink: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path)?)
+        };
+        Ok(EventTap { writer: BufWriter::new(sink) })
+    }
+
+    /// Writes a short message packed as `status | data1 << 8 | data2 << 16`.
+    pub fn write_event(&mut self, wall: f64, time: f64, message: u32) {
+        let status = (message & 0xF0) as u8;
+        let channel = message & 0x0F;
+        let data1 = (message >> 8) & 0x7F;
+        let data2 = (message >> 16) & 0x7F;
+
+        let fields = match status {
+            0x80 => format!("\"kind\":\"note_off\",\"key\":{},\"velocity\":{}", data1, data2),
+            0x90 => format!("\"kind\":\"note_on\",\"key\":{},\"velocity\":{}", data1, data2),
+            0xA0 => format!("\"kind\":\"poly_pressure\",\"key\":{},\"value\":{}", data1, data2),
+            0xB0 => format!("\"kind\":\"control_change\",\"controller\":{},\"value\":{}", data1, data2),
+            0xC0 => format!("\"kind\":\"program_change\",\"program\":{}", data1),
+            0xD0 => format!("\"kind\":\"channel_pressure\",\"value\":{}", data1),
+            0xE0 => format!("\"kind\":\"pitch_bend\",\"value\":{}", ((data2 << 7) | data1) as i32 - 8192),
+            // System messages have no channel and aren't interesting to the tools.
+            _ => return,
+        };
+
+        writeln!(self.writer, "{{\"wall\":{:.6},\"time\":{:.6},\"channel\":{},{}}}", wall, time, channel, fields).ok();
+    }
+
+    pub fn flush(&mut self) {
+        self.writer.flush().ok();
+    }
+}
@@ -0,0 +1,209 @@
+use memmap2::Mmap;
+
+use crate::smf::TrackEvent;
+
+/// A message stored in a [`Timeline`].
+#[derive(Clone, Copy)]
+pub enum Message<'a> {
+    /// A short message packed as `status | data1 << 8 | data2 << 16`.
+    Short(u32),
+    /// A complete SysEx message, including the leading 0xF0.
+    SysEx(&'a [u8]),
+    /// The following events play on another MIDI port.
+    Port(u8),
+}
+
+#[derive(Clone, Copy)]
+pub struct TimelineEvent<'a> {
+    /// Seconds since the previous event.
+    pub delta: f64,
+    /// `None` for events that only move time forward (meta events the player doesn't use).
+    pub message: Option<Message<'a>>,
+}
+
+// Status byte of events without a message.
+const STATUS_NONE: u8 = 0x00;
+const STATUS_SYSEX: u8 = 0xF0;
+// 0xF9 is undefined in MIDI, so it can't clash with a real message.
+const STATUS_PORT: u8 = 0xF9;
+
+enum Columns {
+    Owned {
+        delta: Vec<u8>,
+        status: Vec<u8>,
+        data1: Vec<u8>,
+        data2: Vec<u8>,
+    },
+    /// The same columns back to back in a mapped cache file, starting at `offset`.
+    Mapped {
+        map: Mmap,
+        offset: usize,
+    },
+}
+
+/// Merged events stored as separate packed arrays, 7 bytes per event:
+/// the delta as a little endian f32, then status, data1 and data2.
+///
+/// SysEx data doesn't fit in two data bytes, so it lives in a side table
+/// indexed by the position of its event.
+pub struct Timeline {
+    columns: Columns,
+    len: usize,
+    meta: Vec<(usize, Box<[u8]>)>,
+}
+
+impl Timeline {
+    pub fn with_capacity(capacity: usize) -> Timeline {
+        Timeline {
+            columns: Columns::Owned {
+                delta: Vec::with_capacity(capacity * 4),
+                status: Vec::with_capacity(capacity),
+                data1: Vec::with_capacity(capacity),
+                data2: Vec::with_capacity(capacity),
+            },
+            len: 0,
+            meta: Vec::new(),
+        }
+    }
+
+    /// A read-only timeline over `len` events whose columns start at `offset` in `map`.
+    /// Panics if the map is too small to hold them.
+    pub fn mapped(map: Mmap, offset: usize, len: usize, meta: Vec<(usize, Box<[u8]>)>) -> Timeline {
+        assert!(map.len() >= offset + len * 7);
+        Timeline {
+            columns: Columns::Mapped { map, offset },
+            len,
+            meta,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The delta, status, data1 and data2 columns.
+    pub fn columns(&self) -> [&[u8]; 4] {
+        match &self.columns {
+            Columns::Owned { delta, status, data1, data2 } => [delta, status, data1, data2],
+            Columns::Mapped { map, offset } => {
+                let n = self.len;
+                let start = *offset;
+                [
+                    &map[start..start + n * 4],
+                    &map[start + n * 4..start + n * 5],
+                    &map[start + n * 5..start + n * 6],
+                    &map[start + n * 6..start + n * 7],
+                ]
+            }
+        }
+    }
+
+    /// SysEx messages with the index of the event they belong to.
+    pub fn meta(&self) -> &[(usize, Box<[u8]>)] {
+        &self.meta
+    }
+
+    /// Whether every SysEx message is in order and belongs to a SysEx event. Only looks
+    /// at the events the messages point to, so it's quick on a mapped timeline.
+    pub fn meta_matches(&self) -> bool {
+        let status = self.columns()[1];
+        self.meta.windows(2).all(|pair| pair[0].0 < pair[1].0)
+            && self.meta.iter().all(|(index, _)| status.get(*index) == Some(&STATUS_SYSEX))
+    }
+
+    fn push_raw(&mut self, delta: f64, status: u8, data1: u8, data2: u8) {
+        match &mut self.columns {
+            Columns::Owned { delta: d, status: s, data1: d1, data2: d2 } => {
+                d.extend_from_slice(&(delta as f32).to_le_bytes());
+                s.push(status);
+                d1.push(data1);
+                d2.push(data2);
+            }
+            Columns::Mapped { .. } => panic!("mapped timelines are read-only"),
+        }
+        self.len += 1;
+    }
+
+    /// Adds a short message packed as `status | data1 << 8 | data2 << 16`.
+    pub fn push_short(&mut self, delta: f64, message: u32) {
+        let bytes = message.to_le_bytes();
+        self.push_raw(delta, bytes[0], bytes[1], bytes[2]);
+    }
+
+    /// Adds a SysEx message. `data` is what follows the 0xF0.
+    pub fn push_sysex(&mut self, delta: f64, data: &[u8]) {
+        let mut message = Vec::with_capacity(data.len() + 1);
+        message.push(0xF0);
+        message.extend_from_slice(data);
+        self.meta.push((self.len(), message.into_boxed_slice()));
+        self.push_raw(delta, STATUS_SYSEX, 0, 0);
+    }
+
+    /// Adds a port change.
+    pub fn push_port(&mut self, delta: f64, port: u8) {
+        self.push_raw(delta, STATUS_PORT, port, 0);
+    }
+
+    /// Adds an event read from a MIDI file. Tempo changes are already part of the deltas
+    /// by the time events get here, so they only move time forward.
+    pub fn push_event(&mut self, delta: f64, event: TrackEvent) {
+        match event {
+            TrackEvent::Short(message) => self.push_short(delta, message),
+            TrackEvent::SysEx(data) => self.push_sysex(delta, data),
+            TrackEvent::Port(port) => self.push_port(delta, port),
+            TrackEvent::Tempo(_) => self.push_delta(delta),
+        }
+    }
+
+    /// Adds an event that only moves time forward.
+    pub fn push_delta(&mut self, delta: f64) {
+        self.push_raw(delta, STATUS_NONE, 0, 0);
+    }
+
+    /// Drops the spare capacity left over from loading.
+    pub fn shrink_to_fit(&mut self) {
+        if let Columns::Owned { delta, status, data1, data2 } = &mut self.columns {
+            delta.shrink_to_fit();
+            status.shrink_to_fit();
+            data1.shrink_to_fit();
+            data2.shrink_to_fit();
+        }
+        self.meta.shrink_to_fit();
+    }
+
+    /// Seconds from the previous event to event `index`.
+    pub fn delta(&self, index: usize) -> f64 {
+        let delta = self.columns()[0];
+        let bytes = [delta[index * 4], delta[index * 4 + 1], delta[index * 4 + 2], delta[index * 4 + 3]];
+        f32::from_le_bytes(bytes) as f64
+    }
+
+    /// Event `index`. `next_meta` is the position in [`Timeline::meta`] of the first SysEx
+    /// message from `index` on, and is moved past it when the event is one.
+    ///
+    /// A SysEx event without a message, which only a damaged cache can have, plays nothing.
+    pub fn event(&self, index: usize, next_meta: &mut usize) -> TimelineEvent<'_> {
+        let [_, status, data1, data2] = self.columns();
+        let message = match status[index] {
+            STATUS_NONE => None,
+            STATUS_SYSEX => match self.meta.get(*next_meta) {
+                Some((at, data)) if *at == index => {
+                    *next_meta += 1;
+                    Some(Message::SysEx(data))
+                }
+                _ => None,
+            },
+            STATUS_PORT => Some(Message::Port(data1[index])),
+            status => Some(Message::Short(status as u32 | (data1[index] as u32) << 8 | (data2[index] as u32) << 16)),
+        };
+
+        TimelineEvent {
+            delta: self.delta(index),
+            message,
+        }
+    }
+}
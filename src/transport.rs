@@ -0,0 +1,132 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The longest [`Transport::wait_until`] sleeps before checking for a pause, seek or new rate.
+const MAX_WAIT: f64 = 0.05;
+
+struct State {
+    /// Where playback was when `since` was taken, in seconds of the song.
+    position: f64,
+    since: Instant,
+    paused: bool,
+    rate: f64,
+    seeks: u64,
+}
+
+impl State {
+    fn position(&self) -> f64 {
+        if self.paused {
+            self.position
+        } else {
+            self.position + self.since.elapsed().as_secs_f64() * self.rate
+        }
+    }
+
+    /// Starts counting from the current position again, before anything about the clock changes.
+    fn rebase(&mut self) {
+        self.position = self.position();
+        self.since = Instant::now();
+    }
+}
+
+/// The playback clock every thread follows, so audio and visuals can't drift apart.
+///
+/// Positions are in seconds of the song as loaded, so `-playbackSpeed` is already part
+/// of them and `rate` comes on top.
+pub struct Transport {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Default for Transport {
+    /// A transport playing from the start at normal rate.
+    fn default() -> Transport {
+        Transport {
+            state: Mutex::new(State {
+                position: 0.0,
+                since: Instant::now(),
+                paused: false,
+                rate: 1.0,
+                seeks: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl Transport {
+    pub fn new() -> Transport {
+        Transport::default()
+    }
+
+    pub fn position(&self) -> f64 {
+        self.state.lock().unwrap().position()
+    }
+
+    /// How fast playback runs, 1.0 being the speed the song was loaded with.
+    pub fn rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn toggle_pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.rebase();
+        state.paused = !state.paused;
+        self.changed.notify_all();
+    }
+
+    /// Changes how fast playback runs from the current position on, without jumping.
+    pub fn set_rate(&self, rate: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.rebase();
+        state.rate = rate;
+        self.changed.notify_all();
+    }
+
+    /// Moves playback to `position`, keeping it paused or playing.
+    pub fn seek(&self, position: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.rebase();
+        state.position = position.max(0.0);
+        state.seeks += 1;
+        self.changed.notify_all();
+    }
+
+    /// How many times playback was moved with [`Transport::seek`], so threads can tell it jumped.
+    pub fn seeks(&self) -> u64 {
+        self.state.lock().unwrap().seeks
+    }
+
+    /// Blocks while playback is paused.
+    pub fn wait_while_paused(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.paused {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Blocks until playback reaches `position`, following pauses and rate changes on the way.
+    /// Returns false right away if there were more than `seeks` seeks, the caller has to
+    /// find its place again first.
+    pub fn wait_until(&self, position: f64, seeks: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.seeks != seeks {
+                return false;
+            }
+            if state.paused {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+
+            let ahead = (position - state.position()) / state.rate;
+            if ahead <= 0.0 {
+                return true;
+            }
+
+            drop(state);
+            spin_sleep::sleep(Duration::from_secs_f64(ahead.min(MAX_WAIT)));
+            state = self.state.lock().unwrap();
+        }
+    }
+}